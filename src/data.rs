@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::borrow::Cow;
 use std::collections::{HashSet, LinkedList};
 use std::path::{PathBuf, Path};
@@ -13,6 +13,7 @@ use crate::frontend::Env;
 
 pub mod utils;
 pub mod css;
+pub mod selector;
 
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -25,8 +26,11 @@ pub enum Either<L, R> {
 }
 
 
+/// Shared by every page of the (parallel) build.
+pub type MacroCallback = dyn Fn(&mut crate::macros::MacroContext, &mut Node) + Send + Sync;
+
 #[derive(Clone)]
-pub struct MacroCallbackMut(pub Rc<dyn Fn(&mut Node)>);
+pub struct MacroCallbackMut(pub Arc<MacroCallback>);
 
 impl std::fmt::Debug for MacroCallbackMut {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -106,6 +110,11 @@ impl Node {
         fn render_text(text: &str) -> String {
             text.to_owned()
         }
+        /// Attribute values are stored unescaped (the parser decodes
+        /// character references), and may come from user data, e.g. captions.
+        fn render_attr_value(value: &str) -> String {
+            value.replace('&', "&amp;").replace('"', "&quot;")
+        }
         let level = {
             if indent_level == 0 {
                 String::from("")
@@ -123,7 +132,7 @@ impl Node {
                             if value.is_empty() {
                                 return format!("{}", key);
                             }
-                            format!("{}=\"{}\"", key, value)
+                            format!("{}=\"{}\"", key, render_attr_value(value))
                         })
                         .collect::<Vec<_>>();
                     if ats.is_empty() {
@@ -254,6 +263,93 @@ impl Node {
     //         self.apply(m);
     //     }
     // }
+    /// Descendants (including this node) matching the given CSS selector, in
+    /// document order; see `selector` for what's supported. Fails on invalid
+    /// selectors, for the caller to report (e.g. via `MacroContext::warn`).
+    pub fn select(&self, selector: &str) -> Result<Vec<&Node>, String> {
+        fn go<'a>(
+            node: &'a Node,
+            selector: &selector::Selector,
+            ancestors: &mut Vec<selector::ElementRef<'a>>,
+            out: &mut Vec<&'a Node>,
+        ) {
+            let children = match node {
+                Node::Element(element) => {
+                    let element_ref = selector::ElementRef::from_node(node).unwrap();
+                    if selector.matches(element_ref, ancestors) {
+                        out.push(node);
+                    }
+                    &element.children
+                }
+                Node::Fragment(xs) => xs,
+                Node::Text(_) => return,
+            };
+            if let Some(element_ref) = selector::ElementRef::from_node(node) {
+                ancestors.push(element_ref);
+            }
+            for child in children.iter() {
+                go(child, selector, ancestors, out);
+            }
+            if node.is_element() {
+                ancestors.pop();
+            }
+        }
+        let selector = selector::Selector::parse(selector)?;
+        let mut out = Vec::new();
+        go(self, &selector, &mut Vec::new(), &mut out);
+        Ok(out)
+    }
+    /// Calls `f` on every descendant (including this node) matching the given
+    /// CSS selector. Matches are found up front, and visited in reverse
+    /// document order, so `f` may replace the node it's given. Fails on
+    /// invalid selectors, like `select`.
+    pub fn select_mut(&mut self, selector: &str, mut f: impl FnMut(&mut Node)) -> Result<(), String> {
+        fn paths<'a>(
+            node: &'a Node,
+            selector: &selector::Selector,
+            path: &mut Vec<usize>,
+            ancestors: &mut Vec<selector::ElementRef<'a>>,
+            out: &mut Vec<Vec<usize>>,
+        ) {
+            let children = match node {
+                Node::Element(element) => {
+                    let element_ref = selector::ElementRef::from_node(node).unwrap();
+                    if selector.matches(element_ref, ancestors) {
+                        out.push(path.clone());
+                    }
+                    &element.children
+                }
+                Node::Fragment(xs) => xs,
+                Node::Text(_) => return,
+            };
+            if let Some(element_ref) = selector::ElementRef::from_node(node) {
+                ancestors.push(element_ref);
+            }
+            for (ix, child) in children.iter().enumerate() {
+                path.push(ix);
+                paths(child, selector, path, ancestors, out);
+                path.pop();
+            }
+            if node.is_element() {
+                ancestors.pop();
+            }
+        }
+        let selector = selector::Selector::parse(selector)?;
+        let mut out = Vec::new();
+        paths(self, &selector, &mut Vec::new(), &mut Vec::new(), &mut out);
+        for path in out.into_iter().rev() {
+            let mut node = &mut *self;
+            for ix in path {
+                node = match node {
+                    Node::Element(element) => &mut element.children[ix],
+                    Node::Fragment(xs) => &mut xs[ix],
+                    Node::Text(_) => unreachable!(),
+                };
+            }
+            f(node);
+        }
+        Ok(())
+    }
     pub fn set_tag(&mut self, new_tag: &str) {
         match self {
             Node::Element(element) => {
@@ -299,9 +395,12 @@ impl Node {
             _ => None
         }
     }
-    // pub fn remove_attr(&mut self, key: &str) {
-
-    // }
+    pub fn remove_attr(&mut self, key: &str) -> Option<String> {
+        match self {
+            Node::Element(element) => element.attrs.remove(key),
+            _ => None
+        }
+    }
     pub fn set_attr(&mut self, key: &str, value: String) {
         match self {
             Node::Element(element) => {
@@ -310,6 +409,54 @@ impl Node {
             _ => ()
         }
     }
+    /// Replaces `{{name}}` in text nodes and attribute values with the
+    /// corresponding value; unknown names are left as is. Values are plain
+    /// text: they're escaped in text nodes (which are rendered as is), and
+    /// attribute values are escaped when rendered.
+    pub fn substitute(&mut self, vars: &HashMap<String, String>) {
+        fn substitute_str(
+            text: &str,
+            vars: &HashMap<String, String>,
+            escape: fn(&str) -> String,
+        ) -> String {
+            let mut out = String::new();
+            let mut rest = text;
+            while let Some(start) = rest.find("{{") {
+                out.push_str(&rest[..start]);
+                let after = &rest[start + 2..];
+                match after.find("}}") {
+                    Some(end) if vars.contains_key(after[..end].trim()) => {
+                        out.push_str(&escape(&vars[after[..end].trim()]));
+                        rest = &after[end + 2..];
+                    }
+                    _ => {
+                        out.push_str("{{");
+                        rest = after;
+                    }
+                }
+            }
+            out.push_str(rest);
+            out
+        }
+        match self {
+            Node::Element(element) => {
+                for value in element.attrs.values_mut() {
+                    *value = substitute_str(value, vars, |x| x.to_owned());
+                }
+                for child in element.children.iter_mut() {
+                    child.substitute(vars);
+                }
+            }
+            Node::Fragment(xs) => {
+                for x in xs.iter_mut() {
+                    x.substitute(vars);
+                }
+            }
+            Node::Text(text) => {
+                *text = substitute_str(text, vars, utils::escape_text);
+            }
+        }
+    }
     pub fn replace_children(&mut self, new_children: Vec<Node>) {
         match self {
             Node::Element(element) => {
@@ -440,6 +587,11 @@ impl Node {
     pub fn new_text(value: &str) -> Self {
         Node::Text(String::from(value))
     }
+    /// A text node for plain text, e.g. from a data file, see
+    /// `utils::escape_text`.
+    pub fn new_escaped_text(value: &str) -> Self {
+        Node::Text(utils::escape_text(value))
+    }
     pub fn is_inline_node(&self) -> bool {
         if self.get_attr("block").is_some() {
             return false;
@@ -619,10 +771,9 @@ impl Node {
 // SINGLE USE CELL
 ///////////////////////////////////////////////////////////////////////////////
 
-pub struct Store<T>(Arc<Mutex<T>>);
-
-unsafe impl<T> Send for Store<T> {}
-unsafe impl<T> Sync for Store<T> {}
+/// Shared state, e.g. `Handles`; `Send + Sync` whenever `T` is, so it can be
+/// used from the parallel build.
+pub struct Store<T>(Arc<RwLock<T>>);
 
 impl<T> Clone for Store<T> {
     fn clone(&self) -> Self {Store(self.0.clone())}
@@ -641,19 +792,19 @@ impl<T> std::fmt::Debug for Store<T> {
 
 impl<T> Store<T> {
     pub fn new(x: T) -> Store<T> {
-        Store(Arc::new(Mutex::new(x)))
+        Store(Arc::new(RwLock::new(x)))
     }
+    /// Readers don't block each other.
     pub fn access<U>(&self, f: impl Fn(&T)->U) -> U {
-        use std::ops::DerefMut;
-        let mut lock = self.0.lock().unwrap();
-        f(lock.deref_mut())
+        let lock = self.0.read().unwrap();
+        f(&lock)
     }
     pub fn access_mut<U>(&self, mut f: impl FnMut(&mut T)->U) -> U {
         use std::ops::DerefMut;
-        let mut lock = self.0.lock().unwrap();
+        let mut lock = self.0.write().unwrap();
         f(lock.deref_mut())
     }
-    pub fn into_inner(self) -> Arc<Mutex<T>> {
+    pub fn into_inner(self) -> Arc<RwLock<T>> {
         self.0
     }
 }
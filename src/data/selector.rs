@@ -0,0 +1,424 @@
+//! A subset of CSS selectors, for `Node::select` and `MacroContext::closest`:
+//! tag, `*`, `.class`, `#id`, `[attr]`, `[attr="value"]` (and the `~=`, `|=`,
+//! `^=`, `$=` and `*=` operators) and `:not(...)`, combined via the descendant
+//! (` `) and child (`>`) combinators, in comma separated groups. A backslash
+//! escapes the next character of a name, e.g. `ss\:param`.
+//! Fragments are transparent, e.g. the children of an `<include>`'d fragment
+//! are children of the element it was included in.
+use std::collections::HashMap;
+
+use crate::data::Node;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector(Vec<Complex>);
+
+/// `compounds[0] combinators[0] compounds[1] ...`
+#[derive(Debug, Clone, PartialEq)]
+struct Complex {
+    compounds: Vec<Compound>,
+    combinators: Vec<Combinator>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Compound {
+    tag: Option<String>,
+    ids: Vec<String>,
+    classes: Vec<String>,
+    attrs: Vec<(String, AttrMatch)>,
+    /// `:not(...)`
+    nots: Vec<Selector>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttrMatch {
+    /// `[attr]`
+    Exists,
+    /// `[attr=value]`
+    Equals(String),
+    /// `[attr~=value]`, one of the whitespace separated words.
+    Includes(String),
+    /// `[attr|=value]`, the value or the value followed by `-`.
+    DashMatch(String),
+    /// `[attr^=value]`
+    Prefix(String),
+    /// `[attr$=value]`
+    Suffix(String),
+    /// `[attr*=value]`
+    Substring(String),
+}
+
+impl AttrMatch {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            AttrMatch::Exists => true,
+            AttrMatch::Equals(x) => value == x,
+            AttrMatch::Includes(x) => {
+                !x.is_empty() && value.split_whitespace().any(|word| word == x)
+            }
+            AttrMatch::DashMatch(x) => {
+                value == x || value.strip_prefix(x.as_str()).is_some_and(|rest| rest.starts_with('-'))
+            }
+            AttrMatch::Prefix(x) => !x.is_empty() && value.starts_with(x.as_str()),
+            AttrMatch::Suffix(x) => !x.is_empty() && value.ends_with(x.as_str()),
+            AttrMatch::Substring(x) => !x.is_empty() && value.contains(x.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+/// The tag and attributes of an element, as seen by a selector.
+#[derive(Debug, Clone, Copy)]
+pub struct ElementRef<'a> {
+    pub tag: &'a str,
+    pub attrs: &'a HashMap<String, String>,
+}
+
+impl<'a> ElementRef<'a> {
+    pub fn from_node(node: &'a Node) -> Option<Self> {
+        match node {
+            Node::Element(element) => Some(ElementRef {
+                tag: &element.tag,
+                attrs: &element.attrs,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+impl Selector {
+    pub fn parse(source: &str) -> Result<Selector, String> {
+        let error = |msg: &str| format!("invalid selector {:?}: {}", source, msg);
+        let mut groups = Vec::new();
+        let mut complex = Complex {compounds: Vec::new(), combinators: Vec::new()};
+        let mut compound = Option::<Compound>::None;
+        let mut combinator = Option::<Combinator>::None;
+        let mut chars = source.chars().peekable();
+        let take_ident = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let mut out = String::new();
+            while let Some(c) = chars.peek().cloned().filter(|c| is_ident_char(*c) || *c == '\\') {
+                chars.next();
+                if c == '\\' {
+                    out.extend(chars.next());
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        };
+        // Ends the current compound, if any.
+        fn push(
+            complex: &mut Complex,
+            compound: &mut Option<Compound>,
+            combinator: &mut Option<Combinator>,
+        ) -> Result<(), &'static str> {
+            if let Some(compound) = compound.take() {
+                if !complex.compounds.is_empty() {
+                    complex.combinators.push(combinator.take().unwrap_or(Combinator::Descendant));
+                } else if combinator.is_some() {
+                    return Err("leading combinator");
+                }
+                complex.compounds.push(compound);
+            }
+            Ok(())
+        }
+        while let Some(c) = chars.peek().cloned() {
+            match c {
+                c if c.is_whitespace() => {
+                    chars.next();
+                    push(&mut complex, &mut compound, &mut combinator).map_err(error)?;
+                }
+                '>' => {
+                    chars.next();
+                    push(&mut complex, &mut compound, &mut combinator).map_err(error)?;
+                    if complex.compounds.is_empty() || combinator.is_some() {
+                        return Err(error("misplaced '>'"));
+                    }
+                    combinator = Some(Combinator::Child);
+                }
+                ',' => {
+                    chars.next();
+                    push(&mut complex, &mut compound, &mut combinator).map_err(error)?;
+                    if complex.compounds.is_empty() || combinator.is_some() {
+                        return Err(error("empty selector"));
+                    }
+                    groups.push(std::mem::replace(&mut complex, Complex {
+                        compounds: Vec::new(),
+                        combinators: Vec::new(),
+                    }));
+                }
+                '*' => {
+                    chars.next();
+                    compound.get_or_insert_with(Compound::default);
+                }
+                '.' | '#' => {
+                    chars.next();
+                    let name = take_ident(&mut chars);
+                    if name.is_empty() {
+                        return Err(error("expected a name"));
+                    }
+                    let compound = compound.get_or_insert_with(Compound::default);
+                    if c == '.' {
+                        compound.classes.push(name);
+                    } else {
+                        compound.ids.push(name);
+                    }
+                }
+                '[' => {
+                    chars.next();
+                    let mut name = String::new();
+                    while let Some(c) = chars.peek().cloned().filter(|c| !"=]~|^$*".contains(*c)) {
+                        name.push(c);
+                        chars.next();
+                    }
+                    let operator = match chars.next() {
+                        Some(']') => None,
+                        Some('=') => Some('='),
+                        Some(c) if chars.peek() == Some(&'=') => {
+                            chars.next();
+                            Some(c)
+                        }
+                        _ => return Err(error("unclosed '['")),
+                    };
+                    let value = match operator {
+                        Some(_) => {
+                            let mut value = String::new();
+                            while let Some(c) = chars.peek().cloned().filter(|c| *c != ']') {
+                                value.push(c);
+                                chars.next();
+                            }
+                            if chars.next() != Some(']') {
+                                return Err(error("unclosed '['"));
+                            }
+                            let value = value.trim();
+                            let value = value
+                                .strip_prefix('"').and_then(|x| x.strip_suffix('"'))
+                                .or_else(|| value.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')))
+                                .unwrap_or(value);
+                            value.to_owned()
+                        }
+                        None => String::new(),
+                    };
+                    let attr_match = match operator {
+                        None => AttrMatch::Exists,
+                        Some('=') => AttrMatch::Equals(value),
+                        Some('~') => AttrMatch::Includes(value),
+                        Some('|') => AttrMatch::DashMatch(value),
+                        Some('^') => AttrMatch::Prefix(value),
+                        Some('$') => AttrMatch::Suffix(value),
+                        Some(_) => AttrMatch::Substring(value),
+                    };
+                    let name = name.trim().to_owned();
+                    if name.is_empty() {
+                        return Err(error("expected an attribute name"));
+                    }
+                    compound
+                        .get_or_insert_with(Compound::default)
+                        .attrs
+                        .push((name, attr_match));
+                }
+                ':' => {
+                    chars.next();
+                    if take_ident(&mut chars) != "not" || chars.next() != Some('(') {
+                        return Err(error("unsupported pseudo-class, only `:not(...)` is"));
+                    }
+                    let mut depth = 0;
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some(')') if depth == 0 => break,
+                            Some(c) => {
+                                match c {
+                                    '(' => depth += 1,
+                                    ')' => depth -= 1,
+                                    _ => {}
+                                }
+                                inner.push(c);
+                            }
+                            None => return Err(error("unclosed ':not('")),
+                        }
+                    }
+                    let inner = Selector::parse(&inner).map_err(|msg| error(&msg))?;
+                    compound
+                        .get_or_insert_with(Compound::default)
+                        .nots
+                        .push(inner);
+                }
+                c if is_ident_char(c) => {
+                    let compound = compound.get_or_insert_with(Compound::default);
+                    if compound != &Compound::default() {
+                        return Err(error("the tag must come first"));
+                    }
+                    compound.tag = Some(take_ident(&mut chars).to_lowercase());
+                }
+                c => return Err(error(&format!("unsupported character {:?}", c))),
+            }
+        }
+        push(&mut complex, &mut compound, &mut combinator).map_err(error)?;
+        if complex.compounds.is_empty() || combinator.is_some() {
+            return Err(error("empty selector"));
+        }
+        groups.push(complex);
+        Ok(Selector(groups))
+    }
+    /// Whether `element` matches, given its enclosing elements (innermost last).
+    pub fn matches(&self, element: ElementRef, ancestors: &[ElementRef]) -> bool {
+        self.0.iter().any(|complex| {
+            let last = complex.compounds.len() - 1;
+            complex.compounds[last].matches(element, ancestors) &&
+            complex.matches_ancestors(last, ancestors)
+        })
+    }
+}
+
+impl Complex {
+    /// Whether `compounds[..ix]` match the given ancestors, where
+    /// `compounds[ix]` matched the element they enclose.
+    fn matches_ancestors(&self, ix: usize, ancestors: &[ElementRef]) -> bool {
+        if ix == 0 {
+            return true;
+        }
+        let compound = &self.compounds[ix - 1];
+        match self.combinators[ix - 1] {
+            Combinator::Child => {
+                match ancestors.split_last() {
+                    Some((parent, rest)) => {
+                        compound.matches(*parent, rest) && self.matches_ancestors(ix - 1, rest)
+                    }
+                    None => false,
+                }
+            }
+            Combinator::Descendant => {
+                (0..ancestors.len()).rev().any(|jx| {
+                    compound.matches(ancestors[jx], &ancestors[..jx]) &&
+                    self.matches_ancestors(ix - 1, &ancestors[..jx])
+                })
+            }
+        }
+    }
+}
+
+impl Compound {
+    fn matches(&self, element: ElementRef, ancestors: &[ElementRef]) -> bool {
+        let tag_matches = self.tag
+            .as_ref()
+            .map(|tag| tag == &element.tag.to_lowercase())
+            .unwrap_or(true);
+        let classes = element.attrs
+            .get("class")
+            .map(|x| x.split_whitespace().collect::<Vec<_>>())
+            .unwrap_or_default();
+        tag_matches &&
+        self.ids.iter().all(|id| element.attrs.get("id") == Some(id)) &&
+        self.classes.iter().all(|class| classes.contains(&class.as_str())) &&
+        self.attrs.iter().all(|(name, attr_match)| {
+            element.attrs.get(name).is_some_and(|value| attr_match.matches(value))
+        }) &&
+        !self.nots.iter().any(|x| x.matches(element, ancestors))
+    }
+}
+
+#[cfg(test)]
+mod t_selector {
+    use super::*;
+
+    const HTML: &str = r#"
+        <div id="root" class="page">
+            <section id="intro" lang="en-US" data-tags="draft math">
+                <h2 id="title" class="title big">Intro</h2>
+                <p id="p1" class="note">One</p>
+            </section>
+            <p id="p2" data-src="/images/cat.png">Two</p>
+            <ss:param id="param" name="x"></ss:param>
+        </div>
+    "#;
+
+    fn ids(selector: &str) -> Vec<String> {
+        let html = Node::parse_str(HTML);
+        html.select(selector)
+            .unwrap()
+            .into_iter()
+            .filter_map(|x| x.get_attr("id"))
+            .collect()
+    }
+
+    #[test]
+    fn simple_selectors() {
+        assert_eq!(ids("h2"), vec!["title"]);
+        assert_eq!(ids("#p1"), vec!["p1"]);
+        assert_eq!(ids(".title.big"), vec!["title"]);
+        assert_eq!(ids("p.note"), vec!["p1"]);
+        assert_eq!(ids("section *"), vec!["title", "p1"]);
+        assert_eq!(ids(r"ss\:param"), vec!["param"]);
+    }
+
+    #[test]
+    fn combinators() {
+        assert_eq!(ids("div p"), vec!["p1", "p2"]);
+        assert_eq!(ids("div > p"), vec!["p2"]);
+        assert_eq!(ids("div>section>p"), vec!["p1"]);
+        assert_eq!(ids(".page section > .note"), vec!["p1"]);
+        assert_eq!(ids("section > div p"), Vec::<String>::new());
+        assert_eq!(ids("h2, #p2"), vec!["title", "p2"]);
+    }
+
+    #[test]
+    fn attribute_operators() {
+        assert_eq!(ids("[data-src]"), vec!["p2"]);
+        assert_eq!(ids("[lang=en-US]"), vec!["intro"]);
+        assert_eq!(ids("[lang=\"en\"]"), Vec::<String>::new());
+        assert_eq!(ids("[lang|=en]"), vec!["intro"]);
+        assert_eq!(ids("[lang|=e]"), Vec::<String>::new());
+        assert_eq!(ids("[data-tags~=math]"), vec!["intro"]);
+        assert_eq!(ids("[data-tags~=mat]"), Vec::<String>::new());
+        assert_eq!(ids("[data-src^='/images/']"), vec!["p2"]);
+        assert_eq!(ids("[data-src$=\".png\"]"), vec!["p2"]);
+        assert_eq!(ids("[data-src*=cat]"), vec!["p2"]);
+        assert_eq!(ids("[data-src*='']"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn not() {
+        assert_eq!(ids("p:not(.note)"), vec!["p2"]);
+        assert_eq!(ids("p:not(section > p)"), vec!["p2"]);
+        assert_eq!(ids("section :not(h2, [id=p1])"), Vec::<String>::new());
+        assert_eq!(ids("div > :not(p):not(section)"), vec!["param"]);
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        for selector in vec![
+            "",
+            " ",
+            "> p",
+            "p >",
+            "p > > a",
+            "p,",
+            ",p",
+            "p,,a",
+            ".",
+            "#",
+            "[",
+            "[id",
+            "[=x]",
+            "[id~x]",
+            ".note p.",
+            "p.note.",
+            "p:hover",
+            "p:not(.note",
+            "p:not()",
+            "p + a",
+        ] {
+            assert!(Selector::parse(selector).is_err(), "{:?} should be invalid", selector);
+        }
+        assert!(Node::parse_str(HTML).select("p:hover").is_err());
+    }
+}
@@ -62,6 +62,65 @@ pub fn is_header_tag(tag: &str) -> bool {
     tag == "h6"
 }
 
+/// Compares strings such that embedded numbers are ordered by value, e.g.
+/// `img2.png` before `img10.png`.
+pub fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    /// Splits into runs of digits and non-digits (digits are ASCII, so each
+    /// split is on a char boundary).
+    fn chunks(x: &str) -> Vec<&str> {
+        let bytes = x.as_bytes();
+        let mut out = Vec::new();
+        let mut start = 0;
+        for ix in 1..=bytes.len() {
+            if ix == bytes.len() || bytes[ix].is_ascii_digit() != bytes[ix - 1].is_ascii_digit() {
+                out.push(&x[start..ix]);
+                start = ix;
+            }
+        }
+        out
+    }
+    fn is_number(x: &str) -> bool {
+        x.bytes().all(|x| x.is_ascii_digit())
+    }
+    for (left, right) in chunks(a).into_iter().zip(chunks(b)) {
+        let ordering = if is_number(left) && is_number(right) {
+            let l = left.trim_start_matches('0');
+            let r = right.trim_start_matches('0');
+            l.len().cmp(&r.len()).then_with(|| l.cmp(r))
+        } else {
+            left.cmp(right)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    chunks(a).len().cmp(&chunks(b).len()).then_with(|| a.cmp(b))
+}
+
+/// Escapes `&`, `<` and `>`, for plain text going into a text node (which is
+/// rendered as is).
+pub fn escape_text(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// The SHA-256 of `parts` as hex, each part length-prefixed so adjacent
+/// parts can't run into each other. Unlike `hash_value`, it's stable across
+/// builds and Rust versions, for names that outlive the process.
+pub fn stable_hash(parts: &[&[u8]]) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
 pub fn hash_value<H: std::hash::Hash>(data: &H) -> u64 {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
@@ -0,0 +1,235 @@
+//! Macros implemented by a local executable, in any language, declared in the
+//! manifest:
+//!
+//! ```toml
+//! [[macros.external]]
+//! tag = "plot"
+//! command = ["python3", "plot.py"]
+//! ```
+//!
+//! Each matching element is written to the command's stdin as JSON (see
+//! `Node::to_json`), and replaced by its stdout, parsed as a JSON `Node` if it
+//! starts with `{` or `[`, and as HTML otherwise. Commands running for longer
+//! than `timeout` seconds, or whose output is still open by then, are killed.
+//! Outputs are cached in `.subscript-cache/external` under the project root,
+//! keyed by the SHA-256 of the command, the contents of any files it names,
+//! and the input.
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+
+use crate::data::Node;
+use crate::macros::{Macro, MacroContext, MacroError};
+
+pub const CACHE_DIR: &str = ".subscript-cache/external";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExternalMacro {
+    pub tag: String,
+    /// The program and its arguments, run from the project root.
+    pub command: Vec<String>,
+    /// Set to `false` for commands whose output depends on more than their
+    /// input, e.g. the time or network.
+    #[serde(default = "default_true")]
+    pub cache: bool,
+    /// Seconds after which the command is killed.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// The project root, set when loading the manifest.
+    #[serde(skip)]
+    pub root: PathBuf,
+}
+
+fn default_true() -> bool {true}
+
+fn default_timeout() -> u64 {60}
+
+impl Macro for ExternalMacro {
+    fn name(&self) -> &str {
+        &self.tag
+    }
+    fn tags(&self) -> Vec<String> {
+        vec![self.tag.clone()]
+    }
+    fn expand(&self, ctx: &mut MacroContext, node: &mut Node) -> Result<(), MacroError> {
+        let input = node.to_json();
+        let cache_path = if self.cache {
+            Some(self.cache_path(&input))
+        } else {
+            None
+        };
+        let cached = cache_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|x| Node::from_json(x).ok());
+        if let Some(cached) = cached {
+            *node = cached;
+            return Ok(());
+        }
+        let output = self.run(&input)?;
+        if let Some(path) = cache_path {
+            let written = std::fs::create_dir_all(path.parent().unwrap())
+                .and_then(|_| std::fs::write(&path, output.to_json()));
+            if let Err(msg) = written {
+                ctx.warn(format!("failed to cache output at {:?}: {}", path, msg));
+            }
+        }
+        *node = output;
+        Ok(())
+    }
+}
+
+impl ExternalMacro {
+    /// Keyed by `stable_hash`, as the cache outlives the process.
+    fn cache_path(&self, input: &str) -> PathBuf {
+        let mut parts = Vec::new();
+        for arg in self.command.iter() {
+            parts.push(arg.as_bytes().to_vec());
+            parts.push(std::fs::read(self.root.join(arg)).unwrap_or_default());
+        }
+        parts.push(input.as_bytes().to_vec());
+        let parts = parts.iter().map(|x| x.as_slice()).collect::<Vec<_>>();
+        let uid = crate::data::utils::stable_hash(&parts);
+        self.root.join(CACHE_DIR).join(format!("{}.json", uid))
+    }
+    fn run(&self, input: &str) -> Result<Node, MacroError> {
+        let (program, args) = self.command
+            .split_first()
+            .ok_or_else(|| MacroError::new("empty command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(&self.root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|x| MacroError::new(format!("failed to run {:?}: {}", program, x)))?;
+        // Written from another thread, so commands that write output before
+        // reading all of their input can't deadlock. Never joined, as it may
+        // block on a process the command left behind.
+        let mut stdin = child.stdin.take().unwrap();
+        let input = input.to_owned();
+        std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+        let stdout = read_pipe(child.stdout.take().unwrap());
+        let stderr = read_pipe(child.stderr.take().unwrap());
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        let timed_out = || MacroError::new(format!(
+            "{:?} timed out after {}s",
+            program,
+            self.timeout,
+        ));
+        let status = loop {
+            let status = child
+                .try_wait()
+                .map_err(|x| MacroError::new(format!("failed to run {:?}: {}", program, x)))?;
+            match status {
+                Some(status) => break status,
+                None if Instant::now() >= deadline => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(timed_out());
+                }
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        // Only the command itself was waited for, and anything it started in
+        // the background may still hold its output open.
+        let collect = |pipe: Receiver<Vec<u8>>| {
+            match pipe.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(buffer) => Ok(buffer),
+                Err(RecvTimeoutError::Timeout) => Err(timed_out()),
+                Err(RecvTimeoutError::Disconnected) => Ok(Vec::new()),
+            }
+        };
+        let stdout = collect(stdout)?;
+        let stderr = collect(stderr)?;
+        if !status.success() {
+            return Err(MacroError::new(format!(
+                "{:?} failed ({}): {}",
+                program,
+                status,
+                String::from_utf8_lossy(&stderr).trim(),
+            )));
+        }
+        parse_output(&String::from_utf8_lossy(&stdout))
+    }
+}
+
+/// Reads all of `pipe` from another thread, so neither of the child's output
+/// pipes can fill up while we wait for it.
+fn read_pipe<R: Read + Send + 'static>(mut pipe: R) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = pipe.read_to_end(&mut buffer);
+        let _ = sender.send(buffer);
+    });
+    receiver
+}
+
+/// A JSON `Node` if `output` starts with `{` or `[`, and HTML otherwise.
+pub fn parse_output(output: &str) -> Result<Node, MacroError> {
+    let trimmed = output.trim_start();
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        Node::from_json(trimmed)
+            .map_err(|x| MacroError::new(format!("invalid JSON output: {}", x)))
+    } else {
+        Ok(Node::Fragment(crate::parser::html::parse_html_str(output).payload))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod t_external {
+    use super::*;
+
+    fn external(command: &[&str], timeout: u64) -> ExternalMacro {
+        ExternalMacro {
+            tag: String::from("x"),
+            command: command.iter().map(|x| x.to_string()).collect(),
+            cache: false,
+            timeout,
+            root: PathBuf::from("."),
+        }
+    }
+
+    #[test]
+    fn output() {
+        let node = external(&["cat"], 5).run("<b>hi</b>").unwrap();
+        assert_eq!(node.to_html_str(0), "<b>hi</b>");
+    }
+
+    #[test]
+    fn timeout_kills_the_command() {
+        let start = Instant::now();
+        let error = external(&["sleep", "10"], 1).run("").unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(format!("{:?}", error).contains("timed out after 1s"), "{:?}", error);
+    }
+
+    #[test]
+    fn timeout_with_output_held_open() {
+        let start = Instant::now();
+        let error = external(&["sh", "-c", "sleep 10 & echo hi"], 1).run("").unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(format!("{:?}", error).contains("timed out after 1s"), "{:?}", error);
+    }
+
+    #[test]
+    fn runs_from_the_project_root() {
+        let mut external = external(&["pwd"], 5);
+        external.root = std::env::temp_dir().canonicalize().unwrap();
+        let node = external.run("").unwrap();
+        assert_eq!(node.to_html_str(0).trim(), external.root.to_str().unwrap());
+    }
+
+    #[test]
+    fn stable_cache_key() {
+        let path = external(&["no-such-command", "--flag"], 5).cache_path("{}");
+        assert_eq!(path, PathBuf::from(".").join(CACHE_DIR).join(
+            "4c9efe21d1f761c604d253af9ad7e85c9f2c59fcdc10e5e9df5543a9fe33754a.json"
+        ));
+    }
+}
@@ -0,0 +1,29 @@
+;; A `[[macros.wasm]]` plugin for tests: `<echo>` expands to its input, and
+;; `spin` runs until it's out of fuel.
+(module
+  (import "subscript" "read_file" (func $read_file (param i32 i32) (result i64)))
+  (memory (export "memory") 1)
+  (global $next (mut i32) (i32.const 1024))
+  (data (i32.const 0) "[\"echo\"]")
+
+  (func $pack (param $ptr i32) (param $len i32) (result i64)
+    (i64.or
+      (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+      (i64.extend_i32_u (local.get $len))))
+
+  ;; A bump allocator, reset by each fresh instance.
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $next))
+    (global.set $next (i32.add (global.get $next) (local.get $len)))
+    (local.get $ptr))
+
+  (func (export "tags") (result i64)
+    (call $pack (i32.const 0) (i32.const 8)))
+
+  (func (export "expand") (param $ptr i32) (param $len i32) (result i64)
+    (call $pack (local.get $ptr) (local.get $len)))
+
+  (func (export "spin") (result i64)
+    (loop $forever (br $forever))
+    (i64.const 0)))
@@ -1 +1,3 @@
-pub mod rhai;
\ No newline at end of file
+pub mod rhai;
+pub mod external;
+pub mod wasm;
@@ -0,0 +1,303 @@
+//! Sandboxed WebAssembly macros (requires building with the `wasm` feature),
+//! declared in the manifest:
+//!
+//! ```toml
+//! [[macros.wasm]]
+//! path = "plugins/plot.wasm"
+//! allow_read = ["data"]
+//! ```
+//!
+//! Modules exchange UTF-8 strings with the host through their own memory,
+//! returning them packed as `(ptr << 32) | len`. A module exports:
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, for the host to pass strings to the module.
+//! - `tags() -> i64`, a JSON array of the tags it expands.
+//! - `expand(ptr: i32, len: i32) -> i64`, given the element as a JSON `Node`,
+//!   returning a JSON `Node`, HTML (see `external::parse_output`), or
+//!   `{"error": "..."}`.
+//!
+//! The only import is `subscript.read_file(ptr: i32, len: i32) -> i64`, which
+//! returns the contents of a file under one of the `allow_read` directories,
+//! or `-1`. Modules are compiled and linked once, but each expansion runs in a
+//! fresh instance, limited by `fuel` and `max_memory`.
+use std::path::PathBuf;
+use serde::{Serialize, Deserialize};
+
+use crate::macros::MacroRegistry;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WasmPlugin {
+    /// A `.wasm` (or `.wat`) module.
+    pub path: PathBuf,
+    /// Directories (relative to the project root) the module may read files
+    /// from.
+    #[serde(default)]
+    pub allow_read: Vec<PathBuf>,
+    /// Fuel per expansion, roughly the number of instructions executed;
+    /// defaults to 100 million.
+    pub fuel: Option<u64>,
+    /// Maximum size of the module's memory in bytes, defaults to 64 MiB.
+    pub max_memory: Option<usize>,
+}
+
+impl WasmPlugin {
+    #[cfg(feature = "wasm")]
+    pub fn register(&self, registry: &mut MacroRegistry) -> Result<(), String> {
+        let plugin = host::WasmMacro::load(self)?;
+        for tag in plugin.tags.iter() {
+            if registry.has_tag(tag) {
+                return Err(format!("<{}> is already defined", tag));
+            }
+        }
+        registry.register(plugin);
+        Ok(())
+    }
+    #[cfg(not(feature = "wasm"))]
+    pub fn register(&self, _: &mut MacroRegistry) -> Result<(), String> {
+        Err(String::from("built without the `wasm` feature"))
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod host {
+    use std::path::PathBuf;
+    use wasmtime::{
+        Caller,
+        Config,
+        Engine,
+        Extern,
+        Instance,
+        InstancePre,
+        Linker,
+        Module,
+        Store,
+        StoreLimits,
+        StoreLimitsBuilder,
+    };
+
+    use crate::data::Node;
+    use crate::macros::{Macro, MacroContext, MacroError};
+    use super::WasmPlugin;
+
+    struct State {
+        limits: StoreLimits,
+        /// Canonicalized `WasmPlugin::allow_read`.
+        allow_read: Vec<PathBuf>,
+    }
+
+    pub struct WasmMacro {
+        name: String,
+        pub(super) tags: Vec<String>,
+        engine: Engine,
+        /// The module, linked against the host's imports.
+        instance_pre: InstancePre<State>,
+        allow_read: Vec<PathBuf>,
+        fuel: u64,
+        max_memory: usize,
+    }
+
+    fn pack(ptr: i32, len: usize) -> i64 {
+        ((ptr as u32 as i64) << 32) | len as i64
+    }
+
+    fn unpack(x: i64) -> (usize, usize) {
+        ((x as u64 >> 32) as usize, (x as u64 & 0xFFFF_FFFF) as usize)
+    }
+
+    impl WasmMacro {
+        pub fn load(plugin: &WasmPlugin) -> Result<Self, String> {
+            let error = |msg: String| format!("failed to load {:?}: {}", plugin.path, msg);
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config).map_err(|x| error(x.to_string()))?;
+            let module = Module::from_file(&engine, &plugin.path)
+                .map_err(|x| error(x.to_string()))?;
+            let mut linker = Linker::new(&engine);
+            linker
+                .func_wrap("subscript", "read_file", read_file)
+                .map_err(|x| error(x.to_string()))?;
+            let instance_pre = linker
+                .instantiate_pre(&module)
+                .map_err(|x| error(x.to_string()))?;
+            let allow_read = plugin.allow_read
+                .iter()
+                .filter_map(|dir| match dir.canonicalize() {
+                    Ok(dir) => Some(dir),
+                    Err(_) => {
+                        eprintln!("[warning] ignoring missing directory {:?}", dir);
+                        None
+                    }
+                })
+                .collect();
+            let mut wasm_macro = WasmMacro {
+                name: plugin.path.to_str().unwrap_or_default().to_owned(),
+                tags: Vec::new(),
+                engine,
+                instance_pre,
+                allow_read,
+                fuel: plugin.fuel.unwrap_or(100_000_000),
+                max_memory: plugin.max_memory.unwrap_or(64 << 20),
+            };
+            let tags = wasm_macro
+                .call(|store, instance| {
+                    instance
+                        .get_typed_func::<(), i64>(&mut *store, "tags")?
+                        .call(&mut *store, ())
+                })
+                .map_err(|x| error(x.message))?;
+            wasm_macro.tags = serde_json::from_str(&tags)
+                .map_err(|x| error(format!("invalid tags: {}", x)))?;
+            Ok(wasm_macro)
+        }
+        /// Runs `f` in a fresh instance, returning the string it points to.
+        fn call(
+            &self,
+            f: impl FnOnce(&mut Store<State>, &Instance) -> wasmtime::Result<i64>,
+        ) -> Result<String, MacroError> {
+            let state = State {
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.max_memory)
+                    .instances(1)
+                    .build(),
+                allow_read: self.allow_read.clone(),
+            };
+            let mut store = Store::new(&self.engine, state);
+            store.limiter(|state| &mut state.limits);
+            store
+                .set_fuel(self.fuel)
+                .map_err(|x| MacroError::new(x.to_string()))?;
+            let instance = self.instance_pre
+                .instantiate(&mut store)
+                .map_err(|x| MacroError::new(x.to_string()))?;
+            let packed = f(&mut store, &instance)
+                .map_err(|x| MacroError::new(x.root_cause().to_string()))?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| MacroError::new("missing `memory` export"))?;
+            let (ptr, len) = unpack(packed);
+            let bytes = memory
+                .data(&store)
+                .get(ptr..ptr + len)
+                .ok_or_else(|| MacroError::new("result out of bounds"))?;
+            String::from_utf8(bytes.to_vec())
+                .map_err(|_| MacroError::new("result isn't valid UTF-8"))
+        }
+    }
+
+    /// Writes `bytes` to memory allocated via the module's `alloc` export.
+    fn write_bytes(caller: &mut Caller<'_, State>, bytes: &[u8]) -> wasmtime::Result<i64> {
+        let alloc = caller
+            .get_export("alloc")
+            .and_then(Extern::into_func)
+            .ok_or_else(|| wasmtime::Error::msg("missing `alloc` export"))?
+            .typed::<i32, i32>(&*caller)?;
+        let ptr = alloc.call(&mut *caller, bytes.len() as i32)?;
+        let memory = caller
+            .get_export("memory")
+            .and_then(Extern::into_memory)
+            .ok_or_else(|| wasmtime::Error::msg("missing `memory` export"))?;
+        memory.write(&mut *caller, ptr as u32 as usize, bytes)?;
+        Ok(pack(ptr, bytes.len()))
+    }
+
+    fn read_file(mut caller: Caller<'_, State>, ptr: i32, len: i32) -> wasmtime::Result<i64> {
+        let memory = caller
+            .get_export("memory")
+            .and_then(Extern::into_memory)
+            .ok_or_else(|| wasmtime::Error::msg("missing `memory` export"))?;
+        let mut path = vec![0; len as u32 as usize];
+        memory.read(&caller, ptr as u32 as usize, &mut path)?;
+        let path = PathBuf::from(String::from_utf8_lossy(&path).into_owned());
+        let contents = path
+            .canonicalize()
+            .ok()
+            .filter(|path| caller.data().allow_read.iter().any(|dir| path.starts_with(dir)))
+            .and_then(|path| std::fs::read(path).ok());
+        match contents {
+            Some(contents) => write_bytes(&mut caller, &contents),
+            None => Ok(-1),
+        }
+    }
+
+    impl Macro for WasmMacro {
+        fn name(&self) -> &str {
+            &self.name
+        }
+        fn tags(&self) -> Vec<String> {
+            self.tags.clone()
+        }
+        fn expand(&self, _: &mut MacroContext, node: &mut Node) -> Result<(), MacroError> {
+            let output = self.expand_json(&node.to_json())?;
+            *node = crate::embed::external::parse_output(&output)?;
+            Ok(())
+        }
+    }
+
+    impl WasmMacro {
+        /// Calls the module's `expand` export, see the module docs.
+        fn expand_json(&self, input: &str) -> Result<String, MacroError> {
+            let output = self.call(|store, instance| {
+                let expand = instance.get_typed_func::<(i32, i32), i64>(&mut *store, "expand")?;
+                let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "alloc")?;
+                let memory = instance
+                    .get_memory(&mut *store, "memory")
+                    .ok_or_else(|| wasmtime::Error::msg("missing `memory` export"))?;
+                let ptr = alloc.call(&mut *store, input.len() as i32)?;
+                memory.write(&mut *store, ptr as u32 as usize, input.as_bytes())?;
+                expand.call(&mut *store, (ptr, input.len() as i32))
+            })?;
+            let error = serde_json::from_str::<serde_json::Value>(&output)
+                .ok()
+                .and_then(|x| x.get("error")?.as_str().map(|x| x.to_owned()));
+            if let Some(error) = error {
+                return Err(MacroError::new(error));
+            }
+            Ok(output)
+        }
+    }
+
+    #[cfg(test)]
+    mod t_wasm {
+        use super::*;
+
+        fn load(fuel: u64) -> WasmMacro {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/embed/fixtures/echo.wat");
+            WasmMacro::load(&WasmPlugin {
+                path,
+                allow_read: Vec::new(),
+                fuel: Some(fuel),
+                max_memory: None,
+            }).unwrap()
+        }
+
+        #[test]
+        fn tags() {
+            assert_eq!(load(10_000).tags, vec!["echo"]);
+        }
+
+        #[test]
+        fn expand_in_fresh_instances() {
+            let wasm_macro = load(10_000);
+            let input = Node::parse_str("<echo>hi</echo>").to_json();
+            for _ in 0..3 {
+                assert_eq!(wasm_macro.expand_json(&input).unwrap(), input);
+            }
+            let error = wasm_macro.expand_json(r#"{"error": "oops"}"#).unwrap_err();
+            assert!(format!("{:?}", error).contains("oops"), "{:?}", error);
+        }
+
+        #[test]
+        fn out_of_fuel() {
+            let wasm_macro = load(10_000);
+            let error = wasm_macro
+                .call(|store, instance| {
+                    instance
+                        .get_typed_func::<(), i64>(&mut *store, "spin")?
+                        .call(&mut *store, ())
+                })
+                .unwrap_err();
+            assert!(format!("{:?}", error).contains("fuel"), "{:?}", error);
+        }
+    }
+}
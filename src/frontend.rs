@@ -9,6 +9,7 @@ use crate::data::{Store};
 use rayon::prelude::*;
 
 pub mod browser;
+pub mod images;
 
 ///////////////////////////////////////////////////////////////////////////////
 // CURRENT ENV
@@ -21,11 +22,53 @@ pub struct Env {
     pub base_url: Option<String>,
     pub handles: Store<Handles>,
     pub macro_system: MacroSystem,
+    /// Set when the manifest has an `[images]` table.
+    pub images: Option<images::ImageOptions>,
     pub io_paths: Vec<IoPath>,
+    /// The page currently being built.
+    pub io_path: IoPath,
     pub changed: Option<PathBuf>,
+    /// The page, followed by the `<include>`'d files and components currently
+    /// being expanded, used to detect cycles.
+    pub include_stack: Vec<String>,
+    pub max_include_depth: usize,
+    /// Per page, shared with the page's `<include>`'d files and kept across
+    /// phases.
+    pub metadata: Store<HashMap<String, String>>,
+    /// Shared by every page of the build; pages are built in parallel, so
+    /// nothing should depend on the order of writes.
+    pub shared: Store<HashMap<String, serde_json::Value>>,
+    pub diagnostics: Store<Vec<crate::macros::Diagnostic>>,
 }
 
 impl Env {
+    /// For building `path` on its own, with the built-in macros, in tests.
+    #[cfg(test)]
+    pub(crate) fn for_test(path: &Path) -> Env {
+        let dir = path.parent().unwrap().to_owned();
+        Env {
+            current_dir: dir.clone(),
+            output_dir: dir.join("output"),
+            base_url: None,
+            handles: Store::new(Handles {bibliography: Arc::default()}),
+            macro_system: MacroSystem {
+                registry: Arc::new(crate::macros::MacroRegistry::builtin(&[])),
+            },
+            images: None,
+            io_paths: Vec::new(),
+            io_path: IoPath {
+                input_file: path.to_owned(),
+                output_file: dir.join("output").join(path.file_name().unwrap()),
+                output_dir: dir.join("output"),
+            },
+            changed: None,
+            include_stack: vec![io::display_path(path)],
+            max_include_depth: 32,
+            metadata: Store::new(HashMap::new()),
+            shared: Store::new(HashMap::new()),
+            diagnostics: Store::new(Vec::new()),
+        }
+    }
     pub fn try_load_text_file<P: AsRef<Path>>(&self, path: P) -> Result<(PathBuf, String), ()> {
         let mut path = path.as_ref().to_owned();
         if path.starts_with(".") || path.starts_with("..") {
@@ -44,18 +87,44 @@ pub struct IoPath {
     pub output_dir: PathBuf,
 }
 
+impl IoPath {
+    /// The site-relative route of the compiled page, e.g. `/sample/chapter1.html`.
+    pub fn route(&self) -> String {
+        let path = self.output_file
+            .strip_prefix(&self.output_dir)
+            .unwrap_or(&self.output_file)
+            .iter()
+            .filter_map(|x| x.to_str())
+            .collect::<Vec<_>>()
+            .join("/");
+        format!("/{}", path)
+    }
+}
+
 
 ///////////////////////////////////////////////////////////////////////////////
 // MISC
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Shared by every page of the (parallel) build, so fields must be
+/// `Send + Sync`.
 pub struct Handles {
     // rhai_subsystem: crate::embed::rhai::RhaiSubSystem,
+    /// Read by every page that cites something, so it isn't copied.
+    pub bibliography: Arc<crate::parser::bibtex::Bibliography>,
 }
 
 #[derive(Clone)]
 pub struct MacroSystem {
-    apply_macros: fn(env: &Env, html: &mut crate::data::Node),
+    /// Built once per build and shared by every page.
+    pub registry: Arc<crate::macros::MacroRegistry>,
+}
+
+impl MacroSystem {
+    /// Adds macros on top of the built-in ones, see `main_with`.
+    pub fn register(&mut self, register_macros: impl FnOnce(&mut crate::macros::MacroRegistry)) {
+        register_macros(Arc::make_mut(&mut self.registry));
+    }
 }
 
 impl std::fmt::Debug for MacroSystem {
@@ -72,6 +141,19 @@ impl std::fmt::Debug for MacroSystem {
 pub mod io {
     use super::*;
 
+    /// Relative to the project root where possible, for diagnostics.
+    pub fn display_path<P: AsRef<Path>>(path: P) -> String {
+        let path = std::fs::canonicalize(path.as_ref())
+            .unwrap_or_else(|_| path.as_ref().to_owned());
+        std::env::current_dir()
+            .ok()
+            .and_then(|root| path.strip_prefix(root).ok().map(|x| x.to_owned()))
+            .unwrap_or(path)
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
     pub fn expand_globs(globs: Vec<String>) -> Vec<PathBuf> {
         globs
             .into_iter()
@@ -128,6 +210,25 @@ pub(crate) mod manifest_format {
         pub(crate) root: PathBuf,
         pub(crate) output_dir: PathBuf,
         pub(crate) plugins: Option<Vec<String>>,
+        /// BibTeX file used by the `<cite>` and `<bibliography>` macros.
+        pub(crate) bibliography: Option<PathBuf>,
+        /// Directory of `<define tag="...">` files, defaults to `components`.
+        pub(crate) components: Option<PathBuf>,
+        /// Maximum nesting of `<include>`s and components, defaults to 32.
+        pub(crate) max_include_depth: Option<usize>,
+        /// Expand `{{tag:arg}}`, `@tag{arg}` and `{{page.key}}` in text.
+        #[serde(default)]
+        pub(crate) shortcodes: bool,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    pub struct Macros {
+        /// `[[macros.external]]` entries.
+        #[serde(default)]
+        pub(crate) external: Vec<crate::embed::external::ExternalMacro>,
+        /// `[[macros.wasm]]` entries.
+        #[serde(default)]
+        pub(crate) wasm: Vec<crate::embed::wasm::WasmPlugin>,
     }
 
     #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -143,6 +244,9 @@ pub(crate) mod manifest_format {
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Manifest {
         pub(crate) project: Project,
+        pub(crate) images: Option<images::ImageOptions>,
+        #[serde(default)]
+        pub(crate) macros: Macros,
         #[serde(default)]
         pub(crate) on: OnEvents,
     }
@@ -175,6 +279,11 @@ pub mod config {
         pub handles: Store<Handles>,
         pub macro_system: MacroSystem,
         pub base_url: Option<String>,
+        pub images: Option<images::ImageOptions>,
+        pub max_include_depth: usize,
+        /// Reported while loading the manifest, e.g. about components, and
+        /// printed with every build's diagnostics.
+        pub diagnostics: Vec<crate::macros::Diagnostic>,
     }
     impl Config {
         /// Only call this once.
@@ -202,16 +311,58 @@ pub mod config {
             if !output_dir.exists() {
                 std::fs::create_dir_all(&output_dir);
             }
+            let mut diagnostics = Vec::new();
+            let components = {
+                let dir = manifest.project.components
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from("components"));
+                if dir.exists() {
+                    crate::macros::load_components(&dir, &mut diagnostics)
+                } else {
+                    if manifest.project.components.is_some() {
+                        eprintln!("[warning] missing components directory {:?}", dir);
+                    }
+                    Vec::new()
+                }
+            };
             let handles = {
                 // let rhai_subsystem = crate::embed::rhai::RhaiSubSystem::new(
                 //     manifest.project.plugins.unwrap_or(Vec::new())
                 // );
+                let bibliography = manifest.project.bibliography
+                    .as_ref()
+                    .map(|path| {
+                        crate::parser::bibtex::Bibliography::load(path).unwrap_or_else(|msg| {
+                            eprintln!("[warning] ignoring bibliography: {}", msg);
+                            Default::default()
+                        })
+                    })
+                    .unwrap_or_default();
                 Handles {
                     // rhai_subsystem: rhai_subsystem,
+                    bibliography: Arc::new(bibliography),
                 }
             };
+            let mut registry = crate::macros::MacroRegistry::builtin(&components);
+            if manifest.project.shortcodes {
+                registry.register(crate::macros::Shortcodes);
+            }
+            let root = std::env::current_dir().unwrap();
+            for mut external in manifest.macros.external.iter().cloned() {
+                external.root = root.clone();
+                if registry.has_tag(&external.tag) {
+                    eprintln!("[warning] ignoring external macro for existing tag <{}>", external.tag);
+                    continue;
+                }
+                registry.register(external);
+            }
+            for plugin in manifest.macros.wasm.iter() {
+                if let Err(msg) = plugin.register(&mut registry) {
+                    eprintln!("[warning] ignoring wasm plugin {:?}: {}", plugin.path, msg);
+                }
+            }
             let macro_system = MacroSystem {
-                apply_macros: apply_macros,
+                registry: Arc::new(registry),
             };
             Config {
                 input_files,
@@ -220,6 +371,9 @@ pub mod config {
                 handles: Store::new(handles),
                 macro_system,
                 base_url: None,
+                images: manifest.images.clone().map(images::ImageOptions::validate),
+                max_include_depth: manifest.project.max_include_depth.unwrap_or(32),
+                diagnostics,
             }
         }
     }
@@ -278,7 +432,8 @@ pub mod cache {
     pub fn cache_file(env: &Env, source_path: &str) -> Option<String> {
         GLOBAL_CACHE.cache_file(env, source_path)
     }
-    pub fn cache_file_glob(env: &Env, source: &str) -> Vec<String> {
+    /// Returns the source and output path of each matching file.
+    pub fn cache_file_glob(env: &Env, source: &str) -> Vec<(PathBuf, String)> {
         GLOBAL_CACHE.cache_file_glob(env, source)
     }
     pub fn cache_inline_text(env: &Env, source_path: &str) -> Option<String> {
@@ -331,7 +486,7 @@ pub mod cache {
         /// For file-contents that are inlined in the HTML tree.
         InlineText {contents: String},
         FileGlob {
-            output: Vec<String>,
+            output: Vec<(PathBuf, String)>,
         }
     }
     impl Cache {
@@ -377,7 +532,7 @@ pub mod cache {
                 None
             }
         }
-        fn cache_file_glob(&self, env: &Env, source: &str) -> Vec<String> {
+        fn cache_file_glob(&self, env: &Env, source: &str) -> Vec<(PathBuf, String)> {
             if let Some(CachedItem::FileGlob{output}) = self.lookup(source) {
                 return output;
             }
@@ -393,7 +548,7 @@ pub mod cache {
                     if out_path.is_none() {
                         eprintln!("[warning] ignoring asset: {:?}", path);
                     }
-                    out_path
+                    out_path.map(|out_path| (path, out_path))
                 })
                 .collect::<Vec<_>>();
             let cached_entry = CachedItem::FileGlob {
@@ -411,40 +566,10 @@ pub mod cache {
 ///////////////////////////////////////////////////////////////////////////////
 
 pub fn apply_macros(env: &Env, html: &mut crate::data::Node) {
-    use crate::data::Node;
-    let env = env.clone();
     // let rhai_macros = env.handles.access(|handles: &Handles| {
     //     handles.rhai_subsystem.get_macro_tag_names()
     // });
-    let rust_macros = crate::macros::tag_macros(&env)
-        .into_iter()
-        .map(|x| x.tag)
-        .collect::<HashSet<_>>();
-    html.eval(Rc::new({
-        let env = env.clone();
-        // let rhai_macros = rhai_macros.clone();
-        move |node: &mut Node| {
-            node.tag()
-                .map({
-                    |node_tag| {
-                        // if rhai_macros.clone().contains(&node_tag) {
-                        //     env.handles.access_mut({
-                        //         |handles: &mut Handles| {
-                        //             // handles.rhai_subsystem.consider_node(node)
-                        //         }
-                        //     });
-                        // }
-                        if rust_macros.contains(&node_tag) {
-                            for tag_macro in crate::macros::tag_macros(&env) {
-                                if tag_macro.tag == node_tag {
-                                    (tag_macro.callback.0)(node);
-                                }
-                            }
-                        }
-                    }
-                });
-        }
-    }));
+    crate::macros::registry::apply(env, Arc::default(), crate::macros::Phase::Expand, html);
 }
 
 
@@ -529,6 +654,9 @@ pub fn init(
     (config, io_paths)
 }
 
+/// Builds every page in two passes, the second for macros that depend on
+/// other pages (e.g. `<site-toc>`). Every expanded page is kept in memory
+/// until the second pass, so memory use grows with the size of the site.
 pub fn build(
     config: &config::Config,
     io_paths: &[IoPath],
@@ -536,34 +664,68 @@ pub fn build(
     base_url: Option<String>,
 ) {
     use crate::{data::*};
-    io_paths
+    let shared = Store::new(HashMap::new());
+    let diagnostics = Store::new(config.diagnostics.clone());
+    // FIRST PASS
+    let pages = io_paths
         .clone()
         .into_par_iter()
-        .for_each(|io_path| {
-            let IoPath{input_file: path, output_file: output_path, ..} = io_path;
+        .map(|io_path| {
+            let IoPath{input_file: path, ..} = io_path;
             let env = Env {
                 current_dir: path.parent().unwrap().to_owned(),
                 output_dir: config.output_dir.clone(),
                 base_url: base_url.clone(),
                 handles: config.handles.clone(),
                 macro_system: config.macro_system.clone(),
+                images: config.images.clone(),
                 io_paths: io_paths.to_owned(),
+                io_path: io_path.clone(),
                 changed: changed.clone(),
+                include_stack: vec![io::display_path(path)],
+                max_include_depth: config.max_include_depth,
+                metadata: Store::new(HashMap::new()),
+                shared: shared.clone(),
+                diagnostics: diagnostics.clone(),
             };
             let html = io::load_text_file(&path);
             let mut html = Node::parse_string(html);
             apply_macros(&env, &mut html);
             crate::macros::postproc_document_macros(&env, &mut html);
+            (env, html)
+        })
+        .collect::<Vec<_>>();
+    // SECOND PASS - MACROS THAT DEPEND ON OTHER PAGES
+    let site_pages = pages
+        .iter()
+        .map(|(env, html)| crate::macros::SitePage::new(env, html))
+        .collect::<Arc<[_]>>();
+    pages
+        .into_par_iter()
+        .for_each(|(env, mut html)| {
+            crate::macros::postproc_site_macros(&env, site_pages.clone(), &mut html);
+            let output_path = &env.io_path.output_file;
             let html_str = html.to_html_str(0);
             std::fs::create_dir_all(output_path.parent().unwrap());
-            std::fs::write(&output_path, html_str).unwrap();
+            std::fs::write(output_path, html_str).unwrap();
         });
+    let mut diagnostics = diagnostics.access(|xs| xs.clone());
+    diagnostics.sort_by(|a, b| a.page.cmp(&b.page));
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic);
+    }
 }
 
-pub fn serve(manifest_path: &str, port: u16, open_browser: bool) {
+pub fn serve(
+    manifest_path: &str,
+    port: u16,
+    open_browser: bool,
+    register_macros: impl FnOnce(&mut crate::macros::MacroRegistry),
+) {
     use crate::{data::*};
     use config::Config;
-    let (config, io_paths) = init(manifest_path, None, None);
+    let (mut config, io_paths) = init(manifest_path, None, None);
+    config.macro_system.register(register_macros);
     use hotwatch::{Hotwatch, Event};
     let mut hotwatch = Hotwatch::new().expect("hotwatch failed to initialize!");
     let fast_upate_mode = false;
@@ -636,19 +798,25 @@ pub fn serve(manifest_path: &str, port: u16, open_browser: bool) {
 }
 
 pub fn main() {
+    main_with(|_| {})
+}
+
+/// Entry point for downstream crates that add their own macros, see
+/// `crate::macros::registry`.
+pub fn main_with(register_macros: impl FnOnce(&mut crate::macros::MacroRegistry)) {
     match cli::Cli::from_args() {
         cli::Cli::Compile{manifest, base_url, output_dir} => {
             let output_dir_override = output_dir;
-            let (config, io_paths) = init(
+            let (mut config, io_paths) = init(
                 &manifest,
                 base_url.clone(),
                 output_dir_override,
             );
+            config.macro_system.register(register_macros);
             build(&config, &io_paths, None, base_url);
         }
         cli::Cli::Serve{manifest, port, open_browser} => {
-            serve(&manifest, port, open_browser)
+            serve(&manifest, port, open_browser, register_macros)
         }
     }
 }
-
@@ -0,0 +1,489 @@
+//! Optional image pipeline for `<img>` and `<asset-glob>`, enabled by an
+//! `[images]` table in the manifest. Outputs are named after the SHA-256 of the
+//! source image (and the options used), so unchanged images aren't reprocessed.
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use serde::{Serialize, Deserialize};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+
+use crate::frontend::Env;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+pub struct ImageOptions {
+    /// Widths (in pixels) to generate for `srcset`; widths larger than the
+    /// source image are skipped.
+    #[serde(default)]
+    pub widths: Vec<u32>,
+    /// Additional formats, e.g. `webp` or `avif` (the latter requires building
+    /// with the `avif` feature).
+    #[serde(default)]
+    pub formats: Vec<String>,
+    /// Default `sizes` attribute, if the `<img>` doesn't specify one.
+    pub sizes: Option<String>,
+    /// Drop EXIF and other metadata from the full size image. JPEG and PNG
+    /// files are copied without their metadata segments, unless they have an
+    /// EXIF orientation, in which case (like WebP files) they're re-encoded
+    /// upright.
+    #[serde(default = "default_true")]
+    pub strip_metadata: bool,
+    /// JPEG quality (1-100).
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+}
+
+fn default_true() -> bool {true}
+fn default_quality() -> u8 {85}
+
+impl ImageOptions {
+    /// Drops (with a warning) formats that are unknown or can't be encoded by
+    /// this build, e.g. `avif` without the `avif` feature. Run once when the
+    /// manifest is loaded, rather than for every image.
+    pub fn validate(mut self) -> Self {
+        self.formats.retain(|name| match ImageFormat::from_extension(name) {
+            Some(format) if format.writing_enabled() => true,
+            Some(ImageFormat::Avif) => {
+                eprintln!("[warning] ignoring image format \"avif\": requires building with the `avif` feature");
+                false
+            }
+            _ => {
+                eprintln!("[warning] ignoring unknown image format {:?}", name);
+                false
+            }
+        });
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessedImage {
+    /// The full size image, in the source format.
+    pub src: String,
+    pub width: u32,
+    pub height: u32,
+    /// The `srcset` for the source format.
+    pub srcset: String,
+    /// `(mime type, srcset)` for each additional format.
+    pub sources: Vec<(String, String)>,
+}
+
+/// The displayed width and height, reading only the image's header. Used for
+/// gallery thumbnails when the pipeline is disabled.
+pub fn dimensions(path: &Path) -> Option<(u32, u32)> {
+    let decoder = ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let (width, height, _) = oriented_dimensions(decoder);
+    Some((width, height))
+}
+
+/// Width and height after applying the EXIF orientation, and whether there's
+/// nothing to apply.
+fn oriented_dimensions(mut decoder: impl ImageDecoder) -> (u32, u32, bool) {
+    let (width, height) = decoder.dimensions();
+    match decoder.orientation() {
+        Ok(image::metadata::Orientation::NoTransforms) | Err(_) => (width, height, true),
+        Ok(image::metadata::Orientation::Rotate90) |
+        Ok(image::metadata::Orientation::Rotate270) |
+        Ok(image::metadata::Orientation::Rotate90FlipH) |
+        Ok(image::metadata::Orientation::Rotate270FlipH) => (height, width, false),
+        _ => (width, height, false),
+    }
+}
+
+struct Variant {
+    width: u32,
+    format: ImageFormat,
+    path: PathBuf,
+}
+
+/// What `process_image` needs to know about a source file before checking for
+/// its variants.
+#[derive(Debug, Clone)]
+struct SourceInfo {
+    /// `stable_hash` of the contents.
+    hash: String,
+    width: u32,
+    height: u32,
+    is_upright: bool,
+}
+
+lazy_static! {
+    /// By path and modification time, so a file shown on many pages is only
+    /// read and hashed once.
+    static ref SOURCES: Mutex<HashMap<(PathBuf, SystemTime), SourceInfo>> = Mutex::new(HashMap::new());
+}
+
+/// Writes to a temporary file first, so an interrupted build (or another
+/// page writing the same variant) never leaves a partial file at `path`.
+fn write_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        path.file_name().and_then(|x| x.to_str()).unwrap_or_default(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+    ));
+    std::fs::write(&tmp_path, contents)
+        .and_then(|_| std::fs::rename(&tmp_path, path))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp_path);
+        })
+}
+
+/// Returns `None` if the file can't be read or isn't a raster image we can
+/// encode, in which case callers should fall back to copying it as is.
+pub fn process_image(env: &Env, options: &ImageOptions, source_path: &Path) -> Option<ProcessedImage> {
+    let source_format = ImageFormat::from_path(source_path).ok()?;
+    match source_format {
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {}
+        _ => return None,
+    }
+    let modified = std::fs::metadata(source_path).and_then(|x| x.modified()).ok()?;
+    let key = (source_path.to_owned(), modified);
+    let mut binary = Option::<Vec<u8>>::None;
+    let cached = SOURCES.lock().unwrap().get(&key).cloned();
+    let info = match cached {
+        Some(info) => info,
+        None => {
+            let contents = crate::frontend::io::try_load_binary_file(source_path).ok()?;
+            let (width, height, is_upright) = oriented_dimensions(
+                ImageReader::with_format(Cursor::new(&contents), source_format)
+                    .into_decoder()
+                    .ok()?
+            );
+            let info = SourceInfo {
+                hash: crate::data::utils::stable_hash(&[&contents]),
+                width,
+                height,
+                is_upright,
+            };
+            SOURCES.lock().unwrap().insert(key, info.clone());
+            binary = Some(contents);
+            info
+        }
+    };
+    let SourceInfo {width, height, is_upright, ..} = info;
+    let options_json = serde_json::to_string(options).ok()?;
+    let uid = crate::data::utils::stable_hash(&[info.hash.as_bytes(), options_json.as_bytes()]);
+    let uid = uid.as_str();
+    let mut widths = options.widths
+        .iter()
+        .cloned()
+        .filter(|x| *x < width)
+        .collect::<Vec<_>>();
+    widths.push(width);
+    widths.sort();
+    widths.dedup();
+    // Already checked by `ImageOptions::validate`.
+    let mut formats = vec![source_format];
+    for format in options.formats.iter().filter_map(ImageFormat::from_extension) {
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    let out_dir = env.output_dir.join("ss-data");
+    let variants = formats
+        .iter()
+        .flat_map(|format| {
+            let out_dir = out_dir.clone();
+            widths.iter().map(move |width| Variant {
+                width: *width,
+                format: *format,
+                path: out_dir.join(format!(
+                    "{}-{}.{}",
+                    uid,
+                    width,
+                    format.extensions_str()[0],
+                )),
+            })
+        })
+        .collect::<Vec<_>>();
+    std::fs::create_dir_all(&out_dir).ok()?;
+    let mut decoded = Option::<DynamicImage>::None;
+    let mut written = Vec::<&Variant>::new();
+    for variant in variants.iter() {
+        let is_full_size = variant.width == width && variant.format == source_format;
+        if variant.path.exists() {
+            written.push(variant);
+            continue;
+        }
+        if binary.is_none() {
+            binary = Some(crate::frontend::io::try_load_binary_file(source_path).ok()?);
+        }
+        let binary = binary.as_ref().unwrap();
+        if is_full_size {
+            let copy = match (options.strip_metadata, is_upright) {
+                (false, _) => Some(binary.clone()),
+                (true, true) => strip_metadata(binary, source_format),
+                // Dropping the EXIF orientation would show it sideways.
+                (true, false) => None,
+            };
+            if let Some(copy) = copy {
+                write_file(&variant.path, &copy).ok()?;
+                written.push(variant);
+                continue;
+            }
+        }
+        if decoded.is_none() {
+            decoded = Some(decode(binary, source_format)?);
+        }
+        let image = decoded.as_ref().unwrap();
+        let image = if variant.width < width {
+            let height = (height as u64 * variant.width as u64 / width as u64).max(1) as u32;
+            image.resize_exact(variant.width, height, image::imageops::FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        match encode(&image, variant.format, options.quality) {
+            Ok(bytes) => {
+                write_file(&variant.path, &bytes).ok()?;
+                written.push(variant);
+            }
+            Err(msg) => {
+                eprintln!(
+                    "[warning] failed to encode {:?} as {:?}: {}",
+                    source_path,
+                    variant.format,
+                    msg,
+                );
+            }
+        }
+    }
+    let srcset = |format: ImageFormat| {
+        written
+            .iter()
+            .filter(|x| x.format == format)
+            .map(|x| format!("{} {}w", to_url(env, &x.path), x.width))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let src = written
+        .iter()
+        .find(|x| x.format == source_format && x.width == width)
+        .map(|x| to_url(env, &x.path))?;
+    let sources = formats[1..]
+        .iter()
+        .map(|format| (format.to_mime_type().to_owned(), srcset(*format)))
+        .filter(|(_, srcset)| !srcset.is_empty())
+        .collect::<Vec<_>>();
+    Some(ProcessedImage {
+        src,
+        width,
+        height,
+        srcset: srcset(source_format),
+        sources,
+    })
+}
+
+/// Copies a JPEG or PNG file without its metadata, i.e. without re-encoding
+/// it. Returns `None` for other formats, or if the file is malformed.
+fn strip_metadata(binary: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Jpeg => strip_jpeg_metadata(binary),
+        ImageFormat::Png => strip_png_metadata(binary),
+        _ => None,
+    }
+}
+
+/// Drops `APPn` segments (EXIF, XMP, IPTC, ...) and comments, except for
+/// `APP0` (JFIF), `APP2` (ICC profile) and `APP14` (Adobe color transform),
+/// which affect how the image is displayed.
+fn strip_jpeg_metadata(binary: &[u8]) -> Option<Vec<u8>> {
+    if !binary.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = binary[..2].to_vec();
+    let mut pos = 2;
+    loop {
+        if *binary.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *binary.get(pos + 1)?;
+        match marker {
+            // Padding.
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // Standalone markers.
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&binary[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            // Start of scan: the entropy-coded data (and anything after it)
+            // is copied as is.
+            0xDA | 0xD9 => {
+                out.extend_from_slice(&binary[pos..]);
+                return Some(out);
+            }
+            _ => {}
+        }
+        let len = u16::from_be_bytes([*binary.get(pos + 2)?, *binary.get(pos + 3)?]) as usize;
+        let end = pos + 2 + len;
+        let segment = binary.get(pos..end)?;
+        let is_metadata = match marker {
+            0xE0 | 0xE2 | 0xEE => false,
+            0xE1..=0xEF | 0xFE => true,
+            _ => false,
+        };
+        if !is_metadata {
+            out.extend_from_slice(segment);
+        }
+        pos = end;
+    }
+}
+
+/// Drops the textual, `eXIf` and `tIME` chunks.
+fn strip_png_metadata(binary: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    if !binary.starts_with(&SIGNATURE) {
+        return None;
+    }
+    let mut out = SIGNATURE.to_vec();
+    let mut pos = SIGNATURE.len();
+    while pos < binary.len() {
+        let len = binary.get(pos..pos + 4)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        // Length, type, data and CRC.
+        let end = pos.checked_add(12 + len)?;
+        let chunk = binary.get(pos..end)?;
+        match &chunk[4..8] {
+            b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME" => {}
+            _ => out.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+    Some(out)
+}
+
+fn decode(binary: &[u8], format: ImageFormat) -> Option<DynamicImage> {
+    let mut decoder = ImageReader::with_format(Cursor::new(binary), format)
+        .into_decoder()
+        .ok()?;
+    let orientation = decoder.orientation().ok();
+    let mut image = DynamicImage::from_decoder(decoder).ok()?;
+    if let Some(orientation) = orientation {
+        image.apply_orientation(orientation);
+    }
+    Some(image)
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat, quality: u8) -> Result<Vec<u8>, String> {
+    let mut bytes = Cursor::new(Vec::new());
+    let result = match format {
+        ImageFormat::Jpeg => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut bytes, quality);
+            image.to_rgb8().write_with_encoder(encoder)
+        }
+        ImageFormat::WebP => {
+            // The WebP encoder only supports 8-bit RGB(A).
+            image.to_rgba8().write_to(&mut bytes, format)
+        }
+        _ => image.write_to(&mut bytes, format),
+    };
+    result
+        .map(|_| bytes.into_inner())
+        .map_err(|x| x.to_string())
+}
+
+fn to_url(env: &Env, path: &Path) -> String {
+    let target_path = path
+        .strip_prefix(&env.output_dir)
+        .unwrap_or(path)
+        .to_str()
+        .unwrap()
+        .to_owned();
+    if let Some(base_url) = env.base_url.clone() {
+        let base_url = base_url
+            .strip_suffix("/")
+            .map(|x| x.to_owned())
+            .unwrap_or(base_url);
+        format!("{}/{}", base_url, target_path)
+    } else {
+        format!("/{}", target_path)
+    }
+}
+
+#[cfg(test)]
+mod t_strip_metadata {
+    use super::*;
+
+    fn segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xFF, marker];
+        out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    #[test]
+    fn jpeg() {
+        let scan = [0xFF, 0xDA, 0x00, 0x02, 0x12, 0xFF, 0xE1, 0x34, 0xFF, 0xD9];
+        let keep = [
+            segment(0xE0, b"JFIF\0"),
+            segment(0xE2, b"ICC_PROFILE\0"),
+            segment(0xDB, &[0; 4]),
+        ];
+        let drop = [
+            segment(0xE1, b"Exif\0\0"),
+            segment(0xED, b"Photoshop 3.0\0"),
+            segment(0xFE, b"comment"),
+        ];
+        let mut input = vec![0xFF, 0xD8];
+        let mut expected = vec![0xFF, 0xD8];
+        for (keep, drop) in keep.iter().zip(drop.iter()) {
+            input.extend_from_slice(drop);
+            input.extend_from_slice(keep);
+            expected.extend_from_slice(keep);
+        }
+        input.extend_from_slice(&scan);
+        expected.extend_from_slice(&scan);
+        assert_eq!(strip_jpeg_metadata(&input), Some(expected));
+        assert_eq!(strip_jpeg_metadata(&input[..input.len() - scan.len() - 3]), None);
+        assert_eq!(strip_jpeg_metadata(b"not a jpeg"), None);
+    }
+
+    #[test]
+    fn png() {
+        fn chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut out = (data.len() as u32).to_be_bytes().to_vec();
+            out.extend_from_slice(kind);
+            out.extend_from_slice(data);
+            out.extend_from_slice(&[0; 4]);
+            out
+        }
+        let signature = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        let ihdr = chunk(b"IHDR", &[0; 13]);
+        let text = chunk(b"tEXt", b"Author\0someone");
+        let idat = chunk(b"IDAT", &[1, 2, 3]);
+        let iend = chunk(b"IEND", &[]);
+        let input = [&signature[..], &ihdr, &text, &idat, &iend].concat();
+        let expected = [&signature[..], &ihdr, &idat, &iend].concat();
+        assert_eq!(strip_png_metadata(&input), Some(expected));
+        assert_eq!(strip_png_metadata(&input[..input.len() - 2]), None);
+    }
+}
+
+#[cfg(test)]
+mod t_write_file {
+    use super::*;
+
+    #[test]
+    fn replaces_without_leftovers() {
+        let dir = std::env::temp_dir().join(format!("ss-images-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a-100.jpg");
+        write_file(&path, b"one").unwrap();
+        write_file(&path, b"two").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"two");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
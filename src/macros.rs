@@ -1,5 +1,6 @@
 //! This crate refers to Subscript macros but also includes some misc rust macro helpers.
 use std::rc::Rc;
+use std::sync::Arc;
 use std::cell::RefCell;
 use std::path::{PathBuf, Path};
 use std::collections::HashMap;
@@ -10,6 +11,20 @@ use serde::{Serialize, Deserialize};
 use crate::data::*;
 use crate::frontend::Env;
 
+pub mod registry;
+pub use registry::{
+    Macro,
+    PreOrder,
+    MacroContext,
+    MacroError,
+    MacroRegistry,
+    Phase,
+    Ancestor,
+    Diagnostic,
+    AttrMacro,
+    PassMacro,
+};
+
 
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -45,26 +60,116 @@ macro_rules! html_attrs {
 // TAG MACROS
 ///////////////////////////////////////////////////////////////////////////////
 
-fn include_tag(env: &Env) -> TagMacro {
-    let env = env.clone();
-    let callback = Rc::new(move |node: &mut Node| {
+/// Resolves `{{name}}`, `<ss:param name="...">` and `<if param="..." [equals="..."]>`
+/// (with an optional `<else>`) in an `<include>`'d template, using the
+/// attributes of the `<include>` element. `<param>` and `<slot>` are HTML
+/// elements in their own right, hence the `ss:` prefix.
+fn expand_template_params(template: &mut Node, params: HashMap<String, String>) {
+    template.substitute(&params);
+    template.eval(Rc::new(move |node: &mut Node| {
+        if node.is_tag("ss:param") {
+            let value = node
+                .get_attr("name")
+                .and_then(|name| params.get(&name).cloned())
+                .unwrap_or_default();
+            *node = Node::new_escaped_text(&value);
+        }
+        if node.is_tag("if") {
+            let value = node
+                .get_attr("param")
+                .and_then(|name| params.get(&name).cloned());
+            let is_true = match (value, node.get_attr("equals")) {
+                (Some(value), Some(expected)) => value == expected,
+                (Some(value), None) => !value.is_empty(),
+                (None, _) => false,
+            };
+            let (else_branch, then_branch): (Vec<_>, Vec<_>) = node
+                .get_children()
+                .into_iter()
+                .partition(|x| x.is_tag("else"));
+            *node = if is_true {
+                Node::Fragment(then_branch)
+            } else {
+                Node::Fragment(else_branch.into_iter().flat_map(|x| x.get_children()).collect())
+            };
+        }
+    }));
+}
+
+/// Replaces `node` with `template`, where `template_dir` is the directory
+/// relative paths in the template are resolved against. Shared by `<include>`
+/// and user-defined components, which are identified by `name` on the
+/// include stack; on a cycle or when too deeply nested, `node` is left as is.
+fn expand_template(
+    ctx: &MacroContext,
+    name: String,
+    template_dir: &Path,
+    mut template: Node,
+    node: &mut Node,
+) {
+    let env = ctx.env;
+    if let Some(ix) = env.include_stack.iter().position(|x| x == &name) {
+        let mut chain = env.include_stack[ix..].to_vec();
+        chain.push(name);
+        ctx.warn(format!("include cycle: {}", chain.join(" → ")));
+        return;
+    }
+    if env.include_stack.len() > env.max_include_depth {
+        ctx.warn(format!(
+            "include depth limit ({}) exceeded: {} → {}",
+            env.max_include_depth,
+            env.include_stack.join(" → "),
+            name,
+        ));
+        return;
+    }
+    let mut params = node.get_attributes();
+    params.remove("src");
+    expand_template_params(&mut template, params);
+    let mut template_env = env.clone();
+    template_env.current_dir = template_dir.to_owned();
+    template_env.include_stack.push(name);
+    crate::frontend::apply_macros(&template_env, &mut template);
+    // `<ss:slot name="...">` children fill `<content name="...">`,
+    // everything else fills the unnamed `<content>`.
+    let (slots, embedded): (Vec<_>, Vec<_>) = node
+        .get_children()
+        .into_iter()
+        .partition(|x| x.is_tag("ss:slot") && x.has_attr("name"));
+    let slots = slots
+        .into_iter()
+        .map(|x| (x.get_attr("name").unwrap(), x.get_children()))
+        .collect::<HashMap<_, _>>();
+    template.eval(Rc::new(move |child: &mut Node| {
+        if child.is_tag("content") {
+            let contents = match child.get_attr("name") {
+                Some(name) => slots.get(&name).cloned().unwrap_or_default(),
+                None => embedded.clone(),
+            };
+            // The children of `<content>` are the fallback.
+            if contents.is_empty() {
+                *child = Node::Fragment(child.get_children());
+            } else {
+                *child = Node::Fragment(contents);
+            }
+        }
+    }));
+    *node = template;
+}
+
+pub fn include_tag() -> TagMacro {
+    let callback = Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+        let env = ctx.env;
         node
             .get_attr("src")
             .and_then(|src| env.try_load_text_file(src).ok())
             .map(|(path, text)| {
                 (path, Node::parse_string(text))
             })
-            .map(|(template_path, mut template)| {
-                let mut template_env = env.clone();
-                template_env.current_dir = template_path.parent().unwrap().to_owned();
-                crate::frontend::apply_macros(&template_env, &mut template);
-                let mut embedded = node.get_children();
-                template.eval(Rc::new(move |child: &mut Node| {
-                    if child.is_tag("content") {
-                        *child = Node::Fragment(embedded.clone());
-                    }
-                }));
-                *node = template;
+            .map(|(template_path, template)| {
+                let name = crate::frontend::io::display_path(&template_path);
+                let template_dir = template_path.parent().unwrap().to_owned();
+                expand_template(ctx, name, &template_dir, template, node);
             });
     });
     TagMacro {
@@ -73,10 +178,10 @@ fn include_tag(env: &Env) -> TagMacro {
     }
 }
 
-pub fn img_tag(env: &Env) -> TagMacro {
-    let env = env.clone();
+pub fn img_tag() -> TagMacro {
     let processed_attr = "ss.img.processed";
-    let callback = Rc::new(move |node: &mut Node| {
+    let callback = Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+        let env = ctx.env;
         node
             .get_attr("max-width")
             .map(|width| {
@@ -118,10 +223,18 @@ pub fn img_tag(env: &Env) -> TagMacro {
                 node.set_attr("ss.proc.min-width", String::new());
             });
         // CACHE ASSET
+        if let (Some(options), Some(src_path)) = (env.images.as_ref(), node.get_attr("src")) {
+            if !node.has_attr(processed_attr) && !src_path.starts_with("http") {
+                let path = env.current_dir.join(&src_path);
+                if let Some(new_node) = responsive_image(env, options, &path, node) {
+                    *node = new_node;
+                }
+            }
+        }
         node.get_attr("src")
             .and_then(|src_path| {
                 if !node.has_attr(processed_attr) {
-                    let mut new_src = crate::frontend::cache::cache_file(&env, &src_path)?;
+                    let mut new_src = crate::frontend::cache::cache_file(env, &src_path)?;
                     node.set_attr("src", format!(
                         "{}",
                         new_src
@@ -144,8 +257,49 @@ pub fn img_tag(env: &Env) -> TagMacro {
     }
 }
 
-pub fn latex_suit(env: &Env) -> Vec<TagMacro> {
-    let ctx = env.clone();
+/// Runs the image pipeline on the given file, returning a copy of `img` with
+/// `src`, `srcset`, `sizes`, `width` and `height` set, wrapped in a `<picture>`
+/// if additional formats are configured.
+fn responsive_image(
+    env: &Env,
+    options: &crate::frontend::images::ImageOptions,
+    path: &Path,
+    img: &Node,
+) -> Option<Node> {
+    let image = crate::frontend::images::process_image(env, options, path)?;
+    let sizes = img.get_attr("sizes").or(options.sizes.clone());
+    let mut img = img.clone();
+    img.set_attr("src", image.src);
+    img.set_attr("srcset", image.srcset);
+    if let Some(sizes) = sizes.as_ref() {
+        img.set_attr("sizes", sizes.clone());
+    }
+    if img.get_attr("width").is_none() && img.get_attr("height").is_none() {
+        img.set_attr("width", image.width.to_string());
+        img.set_attr("height", image.height.to_string());
+    }
+    img.set_attr("ss.img.processed", String::new());
+    if image.sources.is_empty() {
+        return Some(img);
+    }
+    let mut children = image.sources
+        .into_iter()
+        .map(|(mime, srcset)| {
+            let mut attrs = html_attrs!{
+                "type": mime,
+                "srcset": srcset,
+            };
+            if let Some(sizes) = sizes.as_ref() {
+                attrs.insert(String::from("sizes"), sizes.clone());
+            }
+            Node::new_element("source", attrs, Vec::new())
+        })
+        .collect::<Vec<_>>();
+    children.push(img);
+    Some(Node::new_element("picture", HashMap::default(), children))
+}
+
+pub fn latex_suit() -> Vec<TagMacro> {
     fn block_latex(macro_name: &str, node: &Node, value: String) -> Node {
         let mut attrs = node.get_attributes();
         attrs.insert(String::from("latex"), String::from("block"));
@@ -169,7 +323,7 @@ pub fn latex_suit(env: &Env) -> Vec<TagMacro> {
     vec![
         TagMacro {
             tag: String::from("tex"),
-            callback: MacroCallbackMut(Rc::new(|node: &mut Node| {
+            callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
                 node.get_text_contents()
                     .map(|text_contents| {
                         let new_node = inline_latex("tex", node, text_contents);
@@ -179,7 +333,7 @@ pub fn latex_suit(env: &Env) -> Vec<TagMacro> {
         },
         TagMacro {
             tag: String::from("texblock"),
-            callback: MacroCallbackMut(Rc::new(|node: &mut Node| {
+            callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
                 node.get_text_contents()
                     .map(|text_contents| {
                         *node = block_latex("texblock", node, text_contents);
@@ -188,7 +342,7 @@ pub fn latex_suit(env: &Env) -> Vec<TagMacro> {
         },
         TagMacro {
             tag: String::from("equation"),
-            callback: MacroCallbackMut(Rc::new(|node: &mut Node| {
+            callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
                 node.get_text_contents()
                     .map(|text_contents| {
                         let new_node = block_latex("equation", node, format!(
@@ -202,40 +356,38 @@ pub fn latex_suit(env: &Env) -> Vec<TagMacro> {
     ]
 }
 
-pub fn subscript_deps(env: &Env) -> TagMacro {
-    let env = env.clone();
+pub fn subscript_deps() -> TagMacro {
     TagMacro {
         tag: String::from("head"),
-        callback: MacroCallbackMut(Rc::new(move |node: &mut Node| {
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
             let mut deps = Node::parse_str(include_str!("../assets/deps.html"));
             node.append_children(deps.into_fragment());
         })),
     }
 }
 
-pub fn note_tag(ctx: &Env) -> TagMacro {
-    let ctx = ctx.clone();
+pub fn note_tag() -> TagMacro {
     TagMacro {
         tag: String::from("note"),
-        callback: MacroCallbackMut(Rc::new(move |node: &mut Node| {
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
             node.set_tag("div");
             node.set_attr("macro", String::from("note"));
         })),
     }
 }
 
-pub fn script_tag(env: &Env) -> TagMacro {
-    let env = env.clone();
+pub fn script_tag() -> TagMacro {
     TagMacro {
         tag: String::from("script"),
-        callback: MacroCallbackMut(Rc::new(move |node: &mut Node| {
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+            let env = ctx.env;
             let processed_key = "ss-script-processed";
             if node.has_attr(processed_key) {
                 return ()
             }
             node.get_attr("src")
                 .and_then(|src| {
-                    crate::frontend::cache::cache_file(&env, &src)
+                    crate::frontend::cache::cache_file(env, &src)
                 })
                 .and_then(|new_path| {
                     node.set_attr("src", new_path);
@@ -246,28 +398,101 @@ pub fn script_tag(env: &Env) -> TagMacro {
     }
 }
 
-pub fn page_nav_tag(env: &Env) -> TagMacro {
-    let env = env.clone();
-    #[derive(Debug, Clone)]
-    struct PageTree {
-        route: Option<String>,
-        title: String,
-        sub_pages: Vec<PageTree>,
+#[derive(Debug, Clone)]
+struct PageTree {
+    route: Option<String>,
+    title: String,
+    sub_pages: Vec<PageTree>,
+}
+
+impl PageTree {
+    /// Parses every `<page>` entry in the given children; `<include>`'d entries
+    /// show up as fragments, so those are flattened.
+    fn from_children(children: Vec<Node>) -> Vec<PageTree> {
+        children
+            .into_iter()
+            .flat_map(|child| match child {
+                Node::Fragment(xs) => PageTree::from_children(xs),
+                child => PageTree::from_node(&child).into_iter().collect(),
+            })
+            .collect()
     }
-    fn build_page_tree(node: &Node) -> Option<PageTree> {
+    fn from_node(node: &Node) -> Option<PageTree> {
         let route = node.get_attr("route");
         let title = node.get_attr("title")?;
-        let sub_pages = node
-            .get_children()
-            .into_iter()
-            .filter_map(|child| build_page_tree(&child))
-            .collect::<Vec<_>>();
+        let sub_pages = PageTree::from_children(node.get_children());
         Some(PageTree {
             route,
             title,
             sub_pages,
         })
     }
+    fn is_current(&self, env: &Env) -> bool {
+        fn normalize(route: &str) -> String {
+            let route = route.trim_start_matches("/");
+            if route.is_empty() || route.ends_with("/") {
+                return format!("{}index.html", route);
+            }
+            route.to_owned()
+        }
+        self.route
+            .as_ref()
+            .map(|route| normalize(route) == normalize(&env.io_path.route()))
+            .unwrap_or(false)
+    }
+    /// The chain of pages from the root entry down to the page being built.
+    fn active_trail(&self, env: &Env) -> Option<Vec<PageTree>> {
+        if self.is_current(env) {
+            return Some(vec![self.clone()]);
+        }
+        self.sub_pages
+            .iter()
+            .find_map(|x| x.active_trail(env))
+            .map(|mut trail| {
+                trail.insert(0, self.clone());
+                trail
+            })
+    }
+    fn compute_route(&self, env: &Env) -> Option<String> {
+        if let Some(route) = self.route.as_ref() {
+            if route.starts_with("/") {
+                if let Some(base_url) = env.base_url.as_ref() {
+                    let route = route.clone();
+                    let base_url: String = base_url
+                        .strip_suffix("/")
+                        .map(|x| x.to_owned())
+                        .unwrap_or(base_url.clone());
+                    let route: String = route
+                        .strip_prefix("/")
+                        .map(|x| x.to_owned())
+                        .unwrap_or(route);
+                    return Some(format!(
+                        "{}/{}",
+                        base_url,
+                        route,
+                    ));
+                }
+            }
+        }
+        return self.route.clone();
+    }
+    fn to_link(&self, env: &Env) -> Node {
+        let mut attrs = HashMap::<String, String>::default();
+        if let Some(route) = self.compute_route(env) {
+            attrs.insert(String::from("href"), route);
+        }
+        if self.is_current(env) {
+            attrs.insert(String::from("aria-current"), String::from("page"));
+        }
+        Node::new_element(
+            "a",
+            attrs,
+            vec![Node::new_text(&self.title)]
+        )
+    }
+}
+
+pub fn page_nav_tag() -> TagMacro {
     fn page_tree_to_html(env: &Env, page: PageTree) -> Node {
         let children = page.sub_pages
             .clone()
@@ -280,60 +505,34 @@ pub fn page_nav_tag(env: &Env) -> TagMacro {
             HashMap::default(),
             children,
         );
-        let compute_route = || -> Option<String> {
-            if let Some(route) = page.route.as_ref() {
-                if route.starts_with("/") {
-                    if let Some(base_url) = env.base_url.as_ref() {
-                        let route = route.clone();
-                        let base_url: String = base_url
-                            .strip_suffix("/")
-                            .map(|x| x.to_owned())
-                            .unwrap_or(base_url.clone());
-                        let route: String = route
-                            .strip_prefix("/")
-                            .map(|x| x.to_owned())
-                            .unwrap_or(route);
-                        return Some(format!(
-                            "{}/{}",
-                            base_url,
-                            route,
-                        ));
-                    }
-                }
-            }
-            return page.route.clone();
-        };
-        let mut attrs = HashMap::<String, String>::default();
-        if let Some(route) = compute_route() {
-            attrs.insert(String::from("href"), route);
+        let link = page.to_link(env);
+        let mut li_attrs = HashMap::<String, String>::default();
+        if page.is_current(env) {
+            li_attrs.insert(String::from("class"), String::from("active"));
+        } else if page.active_trail(env).is_some() {
+            li_attrs.insert(String::from("class"), String::from("active-ancestor"));
         }
-        let link = Node::new_element(
-            "a",
-            attrs,
-            vec![Node::new_text(&page.title)]
-        );
         if empty_children {
             Node::new_element(
                 "li",
-                HashMap::default(),
+                li_attrs,
                 vec![link],
             )
         } else {
             Node::new_element(
                 "li",
-                HashMap::default(),
+                li_attrs,
                 vec![link, child_wrapper],
             )
         }
     }
     TagMacro {
         tag: String::from("page-nav"),
-        callback: MacroCallbackMut(Rc::new(move |node: &mut Node| {
-            let pages = node
-                .get_children()
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+            let env = ctx.env;
+            let pages = PageTree::from_children(node.get_children())
                 .into_iter()
-                .filter_map(|x| build_page_tree(&x))
-                .map(|x| page_tree_to_html(&env, x))
+                .map(|x| page_tree_to_html(env, x))
                 .collect::<Vec<_>>();
             *node = Node::new_element(
                 "ul",
@@ -346,11 +545,49 @@ pub fn page_nav_tag(env: &Env) -> TagMacro {
     }
 }
 
-pub fn layout_tag(ctx: &Env) -> TagMacro {
-    let ctx = ctx.clone();
+/// Renders the path from the site root to the current page, using the same
+/// `<page>` entries as `<page-nav>`.
+pub fn breadcrumbs_tag() -> TagMacro {
+    TagMacro {
+        tag: String::from("breadcrumbs"),
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+            let env = ctx.env;
+            let trail = PageTree::from_children(node.get_children())
+                .into_iter()
+                .find_map(|x| x.active_trail(env));
+            let trail = match trail {
+                Some(trail) => trail,
+                None => {
+                    *node = Node::Fragment(Vec::new());
+                    return;
+                }
+            };
+            let items = trail
+                .into_iter()
+                .map(|page| {
+                    Node::new_element(
+                        "li",
+                        HashMap::default(),
+                        vec![page.to_link(env)],
+                    )
+                })
+                .collect::<Vec<_>>();
+            *node = Node::new_element(
+                "nav",
+                html_attrs!{
+                    "macro": "breadcrumbs",
+                    "aria-label": "Breadcrumb",
+                },
+                vec![Node::new_element("ol", HashMap::default(), items)],
+            );
+        })),
+    }
+}
+
+pub fn layout_tag() -> TagMacro {
     TagMacro {
         tag: String::from("layout"),
-        callback: MacroCallbackMut(Rc::new(move |node: &mut Node| {
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
             node.set_tag("div");
             node.set_attr("macro", String::from("layout"));
             if node.has_attr("boxed") {
@@ -376,11 +613,11 @@ pub fn layout_tag(ctx: &Env) -> TagMacro {
     }
 }
 
-pub fn link_tag(env: &Env) -> TagMacro {
-    let env = env.clone();
+pub fn link_tag() -> TagMacro {
     TagMacro {
         tag: String::from("link"),
-        callback: MacroCallbackMut(Rc::new(move |node: &mut Node| {
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+            let env = ctx.env;
             let processed_attr = "ss.link.processed";
             if node.has_attr(processed_attr) {
                 return ();
@@ -409,8 +646,7 @@ pub fn link_tag(env: &Env) -> TagMacro {
                         crate::frontend::cache::cache_hash_file(&env, href, &contents)
                     }
                     Err(msg) => {
-                        eprintln!("[warning] sass compiler failed:");
-                        eprintln!("{}\n", msg);
+                        ctx.warn(format!("sass compiler failed:\n{}", msg));
                         None
                     }
                 }
@@ -427,10 +663,7 @@ pub fn link_tag(env: &Env) -> TagMacro {
                             );
                             match result {
                                 None => {
-                                    eprintln!(
-                                        "[warning] ignoring asset: {:?}",
-                                        path
-                                    );
+                                    ctx.warn(format!("ignoring asset: {:?}", path));
                                 }
                                 Some(mut out_path) => {
                                     if let Some(base_url) = env.base_url.as_ref() {
@@ -460,7 +693,7 @@ pub fn link_tag(env: &Env) -> TagMacro {
                             Some(())
                         }
                         "css" | _ => {
-                            crate::frontend::cache::cache_file(&env, &href).map(|mut out_path| {
+                            crate::frontend::cache::cache_file(env, &href).map(|mut out_path| {
                                 if let Some(mut base_url) = env.base_url.as_ref() {
                                     let base_url = base_url
                                             .strip_suffix("/")
@@ -487,46 +720,514 @@ pub fn link_tag(env: &Env) -> TagMacro {
     }
 }
 
-pub fn asset_glob_tag(env: &Env) -> TagMacro {
-    let env = env.clone();
+/// Order of the files matched by `<asset-glob>`, given by its `sort` attribute.
+fn sort_assets(ctx: &MacroContext, node: &Node, assets: &mut [Asset]) {
+    let file_name = |path: &PathBuf| {
+        path.file_name()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_owned())
+            .unwrap_or_default()
+    };
+    match node.get_attr("sort").as_deref() {
+        Some("natural") => {
+            assets.sort_by(|a, b| {
+                crate::data::utils::natural_cmp(&file_name(&a.path), &file_name(&b.path))
+            });
+        }
+        Some("mtime") => {
+            assets.sort_by_key(|asset| {
+                std::fs::metadata(&asset.path).and_then(|x| x.modified()).ok()
+            });
+        }
+        Some("name") | None => {
+            assets.sort_by_key(|asset| file_name(&asset.path));
+        }
+        Some(other) => {
+            ctx.warn(format!("unknown sort order {:?}", other));
+        }
+    }
+}
+
+/// The caption of each file in a gallery, if any.
+type Captions = Box<dyn Fn(&Path) -> Option<String>>;
+
+/// Captions for `<asset-glob gallery>`, from its `captions` attribute: either
+/// `filename` (the file stem, with dashes and underscores as spaces), or the
+/// path of a TOML file mapping file names to captions.
+fn gallery_captions(ctx: &MacroContext, node: &Node) -> Captions {
+    let env = ctx.env;
+    let captions = match node.get_attr("captions") {
+        Some(x) => x,
+        None => return Box::new(|_| None),
+    };
+    if captions == "filename" {
+        return Box::new(|path: &Path| {
+            path.file_stem()
+                .and_then(|x| x.to_str())
+                .map(|x| x.replace(['-', '_'], " "))
+        });
+    }
+    let table = env
+        .try_load_text_file(env.current_dir.join(&captions))
+        .map_err(|_| ctx.warn(format!("missing captions file {:?}", captions)))
+        .ok()
+        .and_then(|(_, source)| {
+            toml::from_str::<HashMap<String, String>>(&source)
+                .map_err(|msg| ctx.warn(format!("invalid captions file {:?}: {}", captions, msg)))
+                .ok()
+        })
+        .unwrap_or_default();
+    Box::new(move |path: &Path| {
+        path.file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| table.get(x).cloned())
+    })
+}
+
+/// Wraps each image in a link to the full size image (opened in a lightbox by
+/// the script in `deps.html`), with lazy loading and an optional caption.
+fn gallery_item(thumb_sizes: &str, caption: Option<String>, mut asset: Node) -> Node {
+    let caption_text = caption.clone();
+    let thumb_sizes = thumb_sizes.to_owned();
+    let full_src = Rc::new(RefCell::new(None));
+    asset.eval(Rc::new({
+        let full_src = full_src.clone();
+        move |x: &mut Node| {
+            if x.is_tag("source") {
+                x.set_attr("sizes", thumb_sizes.clone());
+            }
+            if !x.is_tag("img") {
+                return;
+            }
+            *full_src.borrow_mut() = x.get_attr("src");
+            x.set_attr("loading", String::from("lazy"));
+            if x.has_attr("srcset") {
+                x.set_attr("sizes", thumb_sizes.clone());
+            }
+            if let Some(caption) = caption_text.as_ref() {
+                x.set_attr("alt", caption.clone());
+            }
+        }
+    }));
+    let full_src = full_src.borrow().clone().unwrap_or_default();
+    let mut children = vec![Node::new_element(
+        "a",
+        html_attrs!{
+            "href": full_src,
+            "gallery-link": "",
+        },
+        vec![asset],
+    )];
+    if let Some(caption) = caption {
+        children.push(Node::new_element(
+            "figcaption",
+            HashMap::default(),
+            vec![Node::new_escaped_text(&caption)],
+        ));
+    }
+    Node::new_element(
+        "figure",
+        html_attrs!{
+            "gallery-item": "",
+        },
+        children,
+    )
+}
+
+/// A file matched by `<asset-glob>`.
+struct Asset {
+    path: PathBuf,
+    /// The output URL.
+    src: String,
+    /// The default element for the file, e.g. an `<img>`.
+    node: Node,
+}
+
+impl Asset {
+    /// Variables for the `<content>` template of `<asset-glob>`.
+    fn vars(&self) -> HashMap<String, String> {
+        let name = |x: Option<&std::ffi::OsStr>| {
+            x.and_then(|x| x.to_str()).unwrap_or("").to_owned()
+        };
+        let bytes = std::fs::metadata(&self.path).map(|x| x.len()).unwrap_or(0);
+        let size = match bytes {
+            x if x >= 1 << 20 => format!("{:.1} MB", x as f64 / (1 << 20) as f64),
+            x if x >= 1 << 10 => format!("{:.1} KB", x as f64 / (1 << 10) as f64),
+            x => format!("{} B", x),
+        };
+        html_attrs!{
+            "name": {name(self.path.file_name())},
+            "stem": {name(self.path.file_stem())},
+            "ext": {name(self.path.extension())},
+            "path": {self.src.clone()},
+            "size": size,
+            "bytes": bytes,
+            "mime": {asset_mime(&self.path).to_string()},
+        }
+    }
+}
+
+fn asset_mime(path: &Path) -> mime_guess::Mime {
+    mime_guess::from_path(path).first_or_octet_stream()
+}
+
+/// The element used for a file, by MIME type.
+fn asset_element(path: &Path, src: String, is_gallery: bool) -> Node {
+    let mime = asset_mime(path);
+    let file_name = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or("")
+        .to_owned();
+    let download_link = || {
+        Node::new_element(
+            "a",
+            html_attrs!{
+                "href": {src.clone()},
+                "download": {file_name.clone()},
+            },
+            vec![Node::new_escaped_text(&file_name)],
+        )
+    };
+    match mime.type_().as_str() {
+        "image" => {
+            let mut img = Node::new_element(
+                "img",
+                html_attrs!{
+                    "src": {src.clone()},
+                },
+                Vec::new(),
+            );
+            // Galleries open images in a lightbox rather than navigating away.
+            if !is_gallery {
+                img.set_attr("onclick", format!(
+                    "location.href='{}';",
+                    src,
+                ));
+            }
+            img
+        }
+        "video" | "audio" => {
+            Node::new_element(
+                mime.type_().as_str(),
+                html_attrs!{
+                    "src": {src.clone()},
+                    "controls": "",
+                    "preload": "metadata",
+                },
+                vec![download_link()],
+            )
+        }
+        // Sandboxed, since `text/html` files would otherwise run scripts
+        // with the page's origin.
+        "text" => {
+            Node::new_element(
+                "iframe",
+                html_attrs!{
+                    "src": {src.clone()},
+                    "title": {file_name.clone()},
+                    "sandbox": "",
+                    "loading": "lazy",
+                },
+                Vec::new(),
+            )
+        }
+        // Shown by the browser's PDF viewer. Other types are only linked, as
+        // `<object>` would render e.g. HTML or SVG with the page's origin.
+        "application" if mime.subtype() == "pdf" => {
+            Node::new_element(
+                "object",
+                html_attrs!{
+                    "data": {src.clone()},
+                    "type": {mime.essence_str().to_owned()},
+                    "title": {file_name.clone()},
+                },
+                vec![download_link()],
+            )
+        }
+        _ => download_link(),
+    }
+}
+
+/// Expands to the matching files, with an element chosen by MIME type (e.g.
+/// `<img>`, `<video>`, `<audio>`, an `<iframe>` for text, an `<object>` for
+/// PDFs, or a download link). If `<content>` has children, they're repeated
+/// for each file, with `{{name}}`, `{{stem}}`, `{{ext}}`, `{{path}}`,
+/// `{{size}}`, `{{bytes}}` and `{{mime}}` substituted, and `<asset>` replaced
+/// by the default element.
+pub fn asset_glob_tag() -> TagMacro {
     TagMacro {
         tag: String::from("asset-glob"),
-        callback: MacroCallbackMut(Rc::new(move |node: &mut Node| {
-            let asset_nodes = node.get_attr("src")
-                .map(|src| {
-                    crate::frontend::cache::cache_file_glob(&env, &src)
-                })
-                .unwrap_or(Vec::new())
-                .into_iter()
-                .map(|out_path: String| {
-                    Node::new_element(
-                        "img",
-                        html_attrs!{
-                            "src": {
-                                out_path.clone()
-                            },
-                            "onclick": {
-                                format!(
-                                    "location.href='{}';",
-                                    out_path,
-                                )
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+            let env = ctx.env;
+            let is_gallery = node.has_attr("gallery");
+            let mut assets = match (env.images.as_ref(), node.get_attr("src")) {
+                (Some(options), Some(src)) => {
+                    let source = env.current_dir.join(&src);
+                    crate::frontend::io::expand_globs(vec![source.to_str().unwrap().to_owned()])
+                        .into_iter()
+                        .filter_map(|path| {
+                            let img = asset_element(&path, String::new(), true);
+                            let processed = if img.is_tag("img") {
+                                responsive_image(env, options, &path, &img)
+                            } else {
+                                None
+                            };
+                            if let Some(mut node) = processed {
+                                // The `<img>` is the last child of a `<picture>`.
+                                let src = node
+                                    .get_attr("src")
+                                    .or_else(|| node.get_children().last()?.get_attr("src"))
+                                    .unwrap_or_default();
+                                if !is_gallery {
+                                    let onclick = format!("location.href='{}';", src);
+                                    node.eval(Rc::new(move |x: &mut Node| {
+                                        if x.is_tag("img") {
+                                            x.set_attr("onclick", onclick.clone());
+                                        }
+                                    }));
+                                }
+                                return Some(Asset {path, src, node});
                             }
-                        },
-                        Vec::new(),
-                    )
+                            let src = crate::data::utils::cache_file_dep_without_normalizing(
+                                env,
+                                &path,
+                            )?;
+                            let node = asset_element(&path, src.clone(), is_gallery);
+                            Some(Asset {path, src, node})
+                        })
+                        .collect::<Vec<_>>()
+                }
+                _ => {
+                    node.get_attr("src")
+                        .map(|src| {
+                            crate::frontend::cache::cache_file_glob(env, &src)
+                        })
+                        .unwrap_or(Vec::new())
+                        .into_iter()
+                        .map(|(path, src)| {
+                            let mut node = asset_element(&path, src.clone(), is_gallery);
+                            // Without the image pipeline, thumbnails are the
+                            // full size images: `gallery_item` defers loading
+                            // them, and their dimensions reserve the space.
+                            if is_gallery && node.is_tag("img") {
+                                if let Some((width, height)) = crate::frontend::images::dimensions(&path) {
+                                    node.set_attr("width", width.to_string());
+                                    node.set_attr("height", height.to_string());
+                                    node.set_attr("decoding", String::from("async"));
+                                }
+                            }
+                            Asset {path, src, node}
+                        })
+                        .collect::<Vec<_>>()
+                }
+            };
+            sort_assets(ctx, node, &mut assets);
+            let captions = gallery_captions(ctx, node);
+            let thumb_sizes = node
+                .get_attr("thumb-width")
+                .unwrap_or(String::from("240px"));
+            let assets = assets
+                .into_iter()
+                .map(|asset| {
+                    let vars = asset.vars();
+                    let is_image = asset.node.is_tag("img") || asset.node.is_tag("picture");
+                    let node = if is_gallery && is_image {
+                        let caption = captions(&asset.path);
+                        gallery_item(&thumb_sizes, caption, asset.node.clone())
+                    } else {
+                        asset.node.clone()
+                    };
+                    (vars, node)
                 })
                 .collect::<Vec<_>>();
             let mut contents = Node::Fragment(node.get_children());
+            if is_gallery && node.get_children().is_empty() {
+                contents = Node::Fragment(vec![Node::new_element("content", HashMap::default(), Vec::new())]);
+            }
             contents.eval(Rc::new(move |child: &mut Node| {
-                if child.is_tag("content") {
-                    *child = Node::Fragment(asset_nodes.clone());
+                if !child.is_tag("content") {
+                    return;
                 }
+                let template = child.get_children();
+                let asset_nodes = assets
+                    .iter()
+                    .map(|(vars, asset)| {
+                        if template.is_empty() {
+                            return asset.clone();
+                        }
+                        let mut entry = Node::Fragment(template.clone());
+                        entry.substitute(vars);
+                        let asset = asset.clone();
+                        entry.eval(Rc::new(move |x: &mut Node| {
+                            if x.is_tag("asset") {
+                                *x = asset.clone();
+                            }
+                        }));
+                        entry
+                    })
+                    .collect::<Vec<_>>();
+                *child = Node::Fragment(asset_nodes);
             }));
+            if is_gallery {
+                contents = Node::new_element(
+                    "div",
+                    html_attrs!{
+                        "macro": "gallery",
+                    },
+                    contents.into_fragment(),
+                );
+            }
             *node = contents;
         })),
     }
 }
 
+/// A `h1`-`h6` element that has been assigned an `id`.
+#[derive(Debug, Clone)]
+pub struct Heading {
+    pub tag: String,
+    pub id: String,
+    pub text: String,
+}
+
+impl Heading {
+    /// Collects every heading (not marked `toc-ignore`) in document order.
+    pub fn collect(node: &Node) -> Vec<Heading> {
+        fn runner(node: &Node, headings: &mut Vec<Heading>) {
+            match node {
+                Node::Element(element) if crate::data::utils::is_header_tag(&element.tag) => {
+                    if node.has_attr("toc-ignore") {
+                        return;
+                    }
+                    if let Some(id) = element.attrs.get("id") {
+                        headings.push(Heading {
+                            tag: element.tag.clone(),
+                            id: id.clone(),
+                            text: node.get_children_as_text().join(" "),
+                        });
+                    }
+                }
+                Node::Element(element) => {
+                    for child in element.children.iter() {
+                        runner(child, headings);
+                    }
+                }
+                Node::Fragment(xs) => {
+                    for child in xs.iter() {
+                        runner(child, headings);
+                    }
+                }
+                Node::Text(_) => {}
+            }
+        }
+        let mut headings = Vec::new();
+        runner(node, &mut headings);
+        headings
+    }
+    pub fn level(&self) -> usize {
+        self.tag[1..].parse().unwrap_or(6)
+    }
+}
+
+/// Options read from the attributes of a `<toc>` element.
+#[derive(Debug, Clone)]
+struct TocOptions {
+    /// Shallowest heading level to include.
+    from: usize,
+    /// Number of heading levels to include, starting at `from`.
+    depth: usize,
+    numbered: bool,
+}
+
+impl TocOptions {
+    fn new(node: &Node) -> Self {
+        let from = node
+            .get_attr("from")
+            .and_then(|x| x.trim_start_matches("h").parse::<usize>().ok())
+            .filter(|x| *x >= 1 && *x <= 6)
+            .unwrap_or(1);
+        let depth = node
+            .get_attr("depth")
+            .and_then(|x| x.parse::<usize>().ok())
+            .filter(|x| *x >= 1)
+            .unwrap_or(6);
+        TocOptions {
+            from,
+            depth,
+            numbered: node.has_attr("numbered"),
+        }
+    }
+    fn includes(&self, heading: &Heading) -> bool {
+        let level = heading.level();
+        level >= self.from && level < self.from + self.depth
+    }
+}
+
+/// Nests the given headings by level, e.g. an `h3` following an `h2` becomes
+/// a child entry of the latter.
+fn toc_tree(headings: &[Heading], options: &TocOptions, href_prefix: &str) -> Vec<Node> {
+    fn runner(
+        headings: &[Heading],
+        index: &mut usize,
+        parent_level: usize,
+        number_prefix: &str,
+        options: &TocOptions,
+        href_prefix: &str,
+    ) -> Vec<Node> {
+        let mut entries = Vec::new();
+        while *index < headings.len() && headings[*index].level() > parent_level {
+            let heading = &headings[*index];
+            *index += 1;
+            let number = format!("{}{}", number_prefix, entries.len() + 1);
+            let children = runner(
+                headings,
+                index,
+                heading.level(),
+                &format!("{}.", number),
+                options,
+                href_prefix,
+            );
+            let mut link_children = Vec::new();
+            if options.numbered {
+                link_children.push(Node::new_element(
+                    "span",
+                    html_attrs!{
+                        "toc-number": "",
+                    },
+                    vec![Node::new_text(&number)]
+                ));
+                link_children.push(Node::new_text(" "));
+            }
+            link_children.push(Node::new_text(&heading.text));
+            let mut li_children = vec![Node::new_element(
+                "a",
+                html_attrs!{
+                    "href": {format!("{}#{}", href_prefix, heading.id)},
+                },
+                link_children
+            )];
+            if !children.is_empty() {
+                li_children.push(Node::new_element("ul", HashMap::default(), children));
+            }
+            entries.push(Node::new_element(
+                "li",
+                html_attrs!{
+                    "for": {heading.tag.clone()},
+                },
+                li_children
+            ));
+        }
+        entries
+    }
+    let headings = headings
+        .iter()
+        .filter(|x| options.includes(x))
+        .cloned()
+        .collect::<Vec<_>>();
+    runner(&headings, &mut 0, 0, "", options, href_prefix)
+}
+
 pub fn toc_tag(ctx: &Env, html: &mut Node) {
     if !(std::env::var("TOC_RANDOM_LINK") == Ok(String::from("1"))) {
         html.run_tree_annotation();
@@ -552,131 +1253,2239 @@ pub fn toc_tag(ctx: &Env, html: &mut Node) {
             }
         }
     }));
-    fn runner(node: &Node) -> Vec<Node> {
-        let new_entry = |tag: &str, children: String, uid: &String| {
-            let mut li_attrs = HashMap::default();
-            li_attrs.insert(String::from("for"), String::from(tag));
-            let mut a_attrs = HashMap::default();
-            a_attrs.insert(String::from("href"), format!(
-                "#{}",
-                uid
-            ));
-            let result = Node::new_element(
-                "li",
-                li_attrs,
-                vec![Node::new_element(
-                    "a",
-                    a_attrs,
-                    vec![Node::new_text(&children)]
-                )]
-            );
-            vec![result]
-        };
+    fn new_toc(node: &Node, headings: &[Heading]) -> Node {
+        let options = TocOptions::new(node);
+        let mut attrs = node.get_attributes();
+        attrs.insert(String::from("macro"), String::from("toc"));
+        Node::new_element(
+            "ul",
+            attrs,
+            toc_tree(headings, &options, "")
+        )
+    }
+    fn is_scoped_toc(node: &Node) -> bool {
         match node {
-            Node::Element(element) if &element.tag == "h1" && !node.has_attr("toc-ignore") => {
-                let uid = element.attrs.get("id").unwrap();
-                let children = node.get_children_as_text().join(" ");
-                new_entry("h1", children, uid)
-            }
-            Node::Element(element) if &element.tag == "h2" && !node.has_attr("toc-ignore") => {
-                let uid = element.attrs.get("id").unwrap();
-                let children = node.get_children_as_text().join(" ");
-                new_entry("h2", children, uid)
-            }
-            Node::Element(element) if &element.tag == "h3" && !node.has_attr("toc-ignore") => {
-                let uid = element.attrs.get("id").unwrap();
-                let children = node.get_children_as_text().join(" ");
-                new_entry("h3", children, uid)
-            }
-            Node::Element(element) if &element.tag == "h4" && !node.has_attr("toc-ignore") => {
-                let uid = element.attrs.get("id").unwrap();
-                let children = node.get_children_as_text().join(" ");
-                new_entry("h4", children, uid)
-            }
-            Node::Element(element) if &element.tag == "h5" && !node.has_attr("toc-ignore") => {
-                let uid = element.attrs.get("id").unwrap();
-                let children = node.get_children_as_text().join(" ");
-                new_entry("h5", children, uid)
-            }
-            Node::Element(element) if &element.tag == "h6" && !node.has_attr("toc-ignore") => {
-                let uid = element.attrs.get("id").unwrap();
-                let children = node.get_children_as_text().join(" ");
-                new_entry("h6", children, uid)
-            }
-            Node::Element(element) => {
-                return element.children.iter().flat_map(|x| runner(x)).collect()
-            }
-            Node::Fragment(nodes) => {
-                nodes
-                    .iter()
-                    .flat_map(|x| runner(x))
-                    .collect()
-            }
-            _ => Vec::new()
+            Node::Fragment(xs) => xs.iter().any(is_scoped_toc),
+            _ => node.is_tag("toc") && node.has_attr_value("scope", "parent"),
         }
     }
-    let headers = runner(html);
-    html.eval(Rc::new(move |node: &mut Node| {
-        let headers = headers.clone();
-        if node.is_tag("toc") {
-            let mut attrs = node.get_attributes();
-            attrs.insert(String::from("macro"), String::from("toc"));
-            *node = Node::new_element(
-                "ul",
-                attrs,
-                headers
-            );
-        }
-    }));
-}
-
-pub fn markdown_tag(ctx: &Env) -> TagMacro {
-    pub fn compile_markdown(source: String) -> Node {
-        let html_str = {
-            use comrak::{markdown_to_html, ComrakOptions};
-            let mut options = ComrakOptions::default();
-            options.render.unsafe_ = true;
-            options.render.unsafe_ = true;
-            let out = markdown_to_html(&source, &options);
-            out
+    /// `scope="parent"` restricts the listing to headings within the
+    /// enclosing element (at any depth, e.g. within nested `<section>`s).
+    /// Fragments are transparent, so `<include>`'d tocs are scoped to the
+    /// element they're included in.
+    fn runner(node: &mut Node, document_headings: &[Heading], parent_headings: Option<&[Heading]>) {
+        let own_headings = match node {
+            Node::Element(element) if element.children.iter().any(is_scoped_toc) => {
+                Some(Heading::collect(node))
+            }
+            _ => None,
         };
-        Node::parse_str(&html_str)
+        let (children, parent_headings) = match node {
+            Node::Element(element) => (&mut element.children, own_headings.as_deref()),
+            Node::Fragment(xs) => (xs, parent_headings),
+            Node::Text(_) => return,
+        };
+        for child in children.iter_mut() {
+            if child.is_tag("toc") {
+                let headings = match parent_headings {
+                    Some(headings) if child.has_attr_value("scope", "parent") => headings,
+                    _ => document_headings,
+                };
+                *child = new_toc(child, headings);
+            } else {
+                runner(child, document_headings, parent_headings);
+            }
+        }
     }
-    let ctx = ctx.clone();
-    TagMacro {
-        tag: String::from("markdown"),
-        callback: MacroCallbackMut(Rc::new(move |node: &mut Node| {
-            node.get_attr("src")
-                .map(|src| -> PathBuf {
-                    ctx.current_dir.join(src)
-                })
-                .and_then(|src| crate::frontend::io::try_load_text_file(src).ok())
-                .map(|markdown| compile_markdown(markdown))
-                .map(|html| html.unwrap_contents("div"))
-                .map(|html| {
-                    *node = Node::Fragment(html);
-                });
-        })),
+    let headings = Heading::collect(html);
+    if html.is_tag("toc") {
+        *html = new_toc(html, &headings);
+    } else {
+        runner(html, &headings, None);
     }
 }
 
-pub fn tag_macros(env: &Env) -> Vec<TagMacro> {
-    let mut items = vec![
-        include_tag(env),
-        subscript_deps(env),
-        link_tag(&env),
-        page_nav_tag(env),
-        layout_tag(env),
-        asset_glob_tag(&env),
-        img_tag(&env),
-        markdown_tag(&env),
-        note_tag(&env),
-    ];
-    items.append(&mut latex_suit(env));
-    items
+/// Headings, labels and index entries of a compiled page, collected after the
+/// first build pass.
+#[derive(Debug, Clone)]
+pub struct SitePage {
+    pub route: String,
+    pub title: String,
+    pub headings: Vec<Heading>,
+    pub labels: Vec<Label>,
+    pub terms: Vec<IndexEntry>,
+    pub index_entries: Vec<IndexEntry>,
 }
 
-pub fn postproc_document_macros(env: &Env, html: &mut Node) {
-    toc_tag(env, html);
+impl SitePage {
+    pub fn new(env: &Env, html: &Node) -> Self {
+        fn find_title(node: &Node) -> Option<String> {
+            match node {
+                Node::Element(element) if element.tag == "title" => {
+                    node.get_text_contents()
+                }
+                Node::Element(element) => {
+                    element.children.iter().find_map(find_title)
+                }
+                Node::Fragment(xs) => {
+                    xs.iter().find_map(find_title)
+                }
+                Node::Text(_) => None
+            }
+        }
+        let headings = Heading::collect(html);
+        let route = env.io_path.route();
+        let title = headings
+            .iter()
+            .find(|x| x.tag == "h1")
+            .map(|x| x.text.clone())
+            .or_else(|| find_title(html))
+            .unwrap_or_else(|| route.clone());
+        let (terms, index_entries) = IndexEntry::collect(html);
+        SitePage {
+            route,
+            title,
+            headings,
+            labels: Label::collect(html),
+            terms,
+            index_entries,
+        }
+    }
 }
 
+/// A table of contents spanning every page in `Env.io_paths`.
+pub fn site_toc_tag(env: &Env, site_pages: Arc<[SitePage]>, html: &mut Node) {
+    let base_url = env.base_url
+        .as_ref()
+        .map(|x| x.strip_suffix("/").unwrap_or(x).to_owned())
+        .unwrap_or_default();
+    html.eval(Rc::new(move |node: &mut Node| {
+        if !node.is_tag("site-toc") {
+            return;
+        }
+        let options = TocOptions::new(node);
+        let entries = site_pages
+            .iter()
+            .map(|page| {
+                let href = format!("{}{}", base_url, page.route);
+                let headings = toc_tree(&page.headings, &options, &href);
+                let mut children = vec![Node::new_element(
+                    "a",
+                    html_attrs!{
+                        "href": href,
+                    },
+                    vec![Node::new_text(&page.title)]
+                )];
+                if !headings.is_empty() {
+                    children.push(Node::new_element("ul", HashMap::default(), headings));
+                }
+                Node::new_element(
+                    "li",
+                    html_attrs!{
+                        "for": "page",
+                    },
+                    children
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut attrs = node.get_attributes();
+        attrs.insert(String::from("macro"), String::from("site-toc"));
+        *node = Node::new_element(
+            "ul",
+            attrs,
+            entries
+        );
+    }));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// CROSS REFERENCES
+///////////////////////////////////////////////////////////////////////////////
+
+/// Things that can be given a `label` attribute and referenced via `<ref>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelKind {
+    Section,
+    Equation,
+    Figure,
+    Table,
+    /// One of `THEOREM_ENVIRONMENTS`, e.g. `theorem`.
+    Environment(String),
+}
+
+impl LabelKind {
+    pub fn from_node(node: &Node) -> Option<LabelKind> {
+        let tag = node.tag()?;
+        if crate::data::utils::is_header_tag(&tag) {
+            return Some(LabelKind::Section);
+        }
+        if tag == "equation" || node.has_attr_value("macro", "equation") {
+            return Some(LabelKind::Equation);
+        }
+        if node.has_attr("theorem-env") {
+            return node.get_attr("macro").map(LabelKind::Environment);
+        }
+        match &tag[..] {
+            "figure" => Some(LabelKind::Figure),
+            "table" => Some(LabelKind::Table),
+            _ => None,
+        }
+    }
+    /// The default text of a `<ref>` pointing to this kind of label.
+    fn format(&self, number: &str) -> String {
+        match self {
+            LabelKind::Section => format!("§{}", number),
+            LabelKind::Equation => format!("({})", number),
+            LabelKind::Figure => format!("Figure {}", number),
+            LabelKind::Table => format!("Table {}", number),
+            LabelKind::Environment(env) => format!("{} {}", environment_name(env), number),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub name: String,
+    pub kind: LabelKind,
+    pub number: String,
+    pub id: String,
+}
+
+impl Label {
+    /// Collects every labeled element that has been through `numbering_pass`.
+    pub fn collect(node: &Node) -> Vec<Label> {
+        fn runner(node: &Node, labels: &mut Vec<Label>) {
+            let label = LabelKind::from_node(node).and_then(|kind| {
+                Some(Label {
+                    name: node.get_attr("label")?,
+                    kind,
+                    number: node.get_attr("ref-number")?,
+                    id: node.get_attr("id")?,
+                })
+            });
+            if let Some(label) = label {
+                labels.push(label);
+            }
+            match node {
+                Node::Element(element) => {
+                    for child in element.children.iter() {
+                        runner(child, labels);
+                    }
+                }
+                Node::Fragment(xs) => {
+                    for child in xs.iter() {
+                        runner(child, labels);
+                    }
+                }
+                Node::Text(_) => {}
+            }
+        }
+        let mut labels = Vec::new();
+        runner(node, &mut labels);
+        labels
+    }
+}
+
+/// Numbers sections, equations, figures, tables and theorem-like environments
+/// in document order.
+///
+/// Equations (akin to LaTeX's `equation*`) are only counted when labeled or
+/// marked `numbered`. Labeled elements get a `ref-number` attribute, and use
+/// their label as the `id` if they don't already have one.
+pub fn numbering_pass(env: &Env, html: &mut Node) {
+    #[derive(Default)]
+    struct Counters {
+        sections: [usize; 6],
+        equations: usize,
+        figures: usize,
+        tables: usize,
+        environments: HashMap<String, usize>,
+    }
+    let counters = Rc::new(RefCell::new(Counters::default()));
+    html.eval(Rc::new(move |node: &mut Node| {
+        let kind = match LabelKind::from_node(node) {
+            Some(kind) => kind,
+            None => return,
+        };
+        let label = node.get_attr("label");
+        let mut counters = counters.borrow_mut();
+        let number = match kind {
+            LabelKind::Section => {
+                if node.has_attr("toc-ignore") {
+                    return;
+                }
+                let level = node.tag().unwrap()[1..].parse::<usize>().unwrap();
+                counters.sections[level - 1] += 1;
+                for counter in counters.sections[level..].iter_mut() {
+                    *counter = 0;
+                }
+                // Skipped levels are left out, e.g. an `h3` directly under an
+                // `h1` is numbered `1.1`.
+                counters.sections[..level]
+                    .iter()
+                    .filter(|x| **x != 0)
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(".")
+            }
+            LabelKind::Equation => {
+                if label.is_none() && !node.has_attr("numbered") {
+                    return;
+                }
+                counters.equations += 1;
+                let number = counters.equations.to_string();
+                // Alongside the math rather than a `\\tag{}` within it, which
+                // depends on the environment (e.g. one per row of `align`).
+                node.append_children(vec![Node::new_element(
+                    "span",
+                    html_attrs!{
+                        "equation-number": "",
+                    },
+                    vec![Node::new_text(&format!("({})", number))]
+                )]);
+                number
+            }
+            LabelKind::Figure => {
+                counters.figures += 1;
+                let number = counters.figures.to_string();
+                if node.has_attr_value("macro", "figure-box") {
+                    figure_box_caption(node, &number);
+                }
+                number
+            }
+            LabelKind::Table => {
+                counters.tables += 1;
+                counters.tables.to_string()
+            }
+            LabelKind::Environment(env) => {
+                if env == "proof" || node.has_attr("unnumbered") {
+                    return;
+                }
+                let counter = node.get_attr("counter").unwrap_or(env);
+                let counter = counters.environments.entry(counter).or_insert(0);
+                *counter += 1;
+                let number = counter.to_string();
+                theorem_env_number(node, &number);
+                number
+            }
+        };
+        if let Some(label) = label {
+            node.set_attr("ref-number", number);
+            if node.get_attr("id").is_none() {
+                node.set_attr("id", label);
+            }
+        }
+    }));
+}
+
+/// Prefixes the `<figcaption>` of a `<figure-box>` with its number, and gives
+/// it an `id` so that `<list-of-figures>` can link to it.
+fn figure_box_caption(node: &mut Node, number: &str) {
+    node.set_attr("ref-number", number.to_owned());
+    if node.get_attr("id").is_none() && node.get_attr("label").is_none() {
+        node.set_attr("id", format!("figure-{}", number));
+    }
+    if let Node::Element(element) = node {
+        for child in element.children.iter_mut() {
+            if child.is_tag("figcaption") {
+                let mut children = vec![
+                    Node::new_element(
+                        "span",
+                        html_attrs!{
+                            "figure-number": "",
+                        },
+                        vec![Node::new_text(&format!("Figure {}:", number))]
+                    ),
+                    Node::new_text(" "),
+                ];
+                children.append(&mut child.get_children());
+                child.replace_children(children);
+            }
+        }
+    }
+}
+
+pub fn figure_box_tag() -> TagMacro {
+    TagMacro {
+        tag: String::from("figure-box"),
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+            let mut attrs = node.get_attributes();
+            attrs.insert(String::from("macro"), String::from("figure-box"));
+            let mut children = node.get_children();
+            if let Some(caption) = attrs.remove("caption") {
+                children.push(Node::new_element(
+                    "figcaption",
+                    HashMap::default(),
+                    vec![Node::new_escaped_text(&caption)]
+                ));
+            }
+            *node = Node::new_element("figure", attrs, children);
+        })),
+    }
+}
+
+/// Lists every `<figure-box>` on the page, with the contents of their
+/// `<figcaption>`; must run after `numbering_pass`.
+pub fn list_of_figures_tag(env: &Env, html: &mut Node) {
+    fn runner(node: &Node, entries: &mut Vec<Node>) {
+        if node.has_attr_value("macro", "figure-box") {
+            let number = node.get_attr("ref-number");
+            let id = node.get_attr("id");
+            if let (Some(number), Some(id)) = (number, id) {
+                let mut text = vec![Node::new_text(&format!("Figure {}", number))];
+                let caption = node
+                    .get_children()
+                    .into_iter()
+                    .find(|x| x.is_tag("figcaption"))
+                    .map(|x| x.get_children());
+                if let Some(mut caption) = caption {
+                    // Without the prefix added by `figure_box_caption`.
+                    if caption.first().is_some_and(|x| x.has_attr("figure-number")) {
+                        caption.drain(..caption.len().min(2));
+                    }
+                    text.push(Node::new_text(": "));
+                    text.append(&mut caption);
+                }
+                entries.push(Node::new_element(
+                    "li",
+                    HashMap::default(),
+                    vec![Node::new_element(
+                        "a",
+                        html_attrs!{
+                            "href": {format!("#{}", id)},
+                        },
+                        text
+                    )]
+                ));
+            }
+        }
+        match node {
+            Node::Element(element) => {
+                for child in element.children.iter() {
+                    runner(child, entries);
+                }
+            }
+            Node::Fragment(xs) => {
+                for child in xs.iter() {
+                    runner(child, entries);
+                }
+            }
+            Node::Text(_) => {}
+        }
+    }
+    let mut entries = Vec::new();
+    runner(html, &mut entries);
+    html.eval(Rc::new(move |node: &mut Node| {
+        if node.is_tag("list-of-figures") {
+            let mut attrs = node.get_attributes();
+            attrs.insert(String::from("macro"), String::from("list-of-figures"));
+            *node = Node::new_element(
+                "ul",
+                attrs,
+                entries.clone()
+            );
+        }
+    }));
+}
+
+/// Resolves `<ref to="label">` against the current page first, and then every
+/// other page in the project. Any children of the `<ref>` are kept as a prefix,
+/// e.g. `<ref to="eq-1">Equation</ref>` renders as “Equation (1)”.
+pub fn ref_tag(ctx: &MacroContext, html: &mut Node) {
+    let env = ctx.env;
+    let site_pages = ctx.site_pages.clone();
+    let unknown = Rc::new(RefCell::new(Vec::<String>::new()));
+    let base_url = env.base_url
+        .as_ref()
+        .map(|x| x.strip_suffix("/").unwrap_or(x).to_owned())
+        .unwrap_or_default();
+    let current_route = env.io_path.route();
+    html.eval(Rc::new({
+        let unknown = unknown.clone();
+        move |node: &mut Node| {
+            if !node.is_tag("ref") {
+                return;
+            }
+            let name = node.get_attr("to").unwrap_or_default();
+            let lookup = |page: &SitePage| {
+                page.labels
+                    .iter()
+                    .find(|x| x.name == name)
+                    .map(|label| (page.route.clone(), label.clone()))
+            };
+            let resolved = site_pages
+                .iter()
+                .filter(|page| page.route == current_route)
+                .find_map(lookup)
+                .or_else(|| site_pages.iter().find_map(lookup));
+            let mut children = node.get_children();
+            if !children.is_empty() {
+                children.push(Node::new_text(" "));
+            }
+            match resolved {
+                Some((route, label)) => {
+                    let href = if route == current_route {
+                        format!("#{}", label.id)
+                    } else {
+                        format!("{}{}#{}", base_url, route, label.id)
+                    };
+                    children.push(Node::new_text(&label.kind.format(&label.number)));
+                    *node = Node::new_element(
+                        "a",
+                        html_attrs!{
+                            "macro": "ref",
+                            "href": href,
+                        },
+                        children,
+                    );
+                }
+                None => {
+                    unknown.borrow_mut().push(name);
+                    children.push(Node::new_text("??"));
+                    *node = Node::new_element(
+                        "span",
+                        html_attrs!{
+                            "macro": "ref",
+                            "unresolved": "",
+                        },
+                        children,
+                    );
+                }
+            }
+        }
+    }));
+    for name in unknown.borrow().iter() {
+        ctx.warn(format!("unknown label {:?}", name));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// CITATIONS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CitationStyle {
+    /// `[1]`, with entries listed in order of first citation.
+    Numeric,
+    /// `(Knuth, 1984)`, with entries sorted by author.
+    AuthorYear,
+}
+
+impl CitationStyle {
+    fn new(name: &str) -> Option<Self> {
+        match name {
+            "numeric" | "ieee" => Some(CitationStyle::Numeric),
+            "author-year" | "apa" => Some(CitationStyle::AuthorYear),
+            _ => None,
+        }
+    }
+}
+
+/// The authors and year of an author-year citation, e.g. `("Knuth", "1984")`.
+fn author_year(entry: &crate::parser::bibtex::Entry) -> (String, Option<String>) {
+    let surnames = entry.surnames();
+    let authors = match surnames.len() {
+        0 => entry.field("title").unwrap_or(&entry.key).to_owned(),
+        1 => surnames[0].clone(),
+        2 => format!("{} & {}", surnames[0], surnames[1]),
+        _ => format!("{} et al.", surnames[0]),
+    };
+    (authors, entry.field("year").map(|x| x.to_owned()))
+}
+
+/// Years of the given (cited) entries, with a suffix for entries sharing the
+/// same authors and year, e.g. `2020a` and `2020b`, ordered by title.
+fn author_year_labels<'a>(
+    entries: &[&'a crate::parser::bibtex::Entry]
+) -> HashMap<&'a str, String> {
+    let mut groups = HashMap::<(String, String), Vec<&crate::parser::bibtex::Entry>>::new();
+    for entry in entries {
+        if let (authors, Some(year)) = author_year(entry) {
+            groups.entry((authors, year)).or_default().push(entry);
+        }
+    }
+    let mut labels = HashMap::new();
+    for ((_, year), mut group) in groups {
+        if group.len() == 1 {
+            labels.insert(group[0].key.as_str(), year);
+            continue;
+        }
+        group.sort_by_key(|entry| (entry.field("title").unwrap_or_default(), &entry.key));
+        for (ix, entry) in group.into_iter().enumerate() {
+            let suffix = std::char::from_u32('a' as u32 + ix as u32 % 26).unwrap_or('a');
+            labels.insert(entry.key.as_str(), format!("{}{}", year, suffix));
+        }
+    }
+    labels
+}
+
+fn bibliography_entry(
+    entry: &crate::parser::bibtex::Entry,
+    label: Option<String>,
+    year: Option<&str>,
+) -> Node {
+    let mut children = Vec::new();
+    if let Some(label) = label {
+        children.push(Node::new_element(
+            "span",
+            html_attrs!{
+                "bib-label": "",
+            },
+            vec![Node::new_escaped_text(&label)]
+        ));
+        children.push(Node::new_text(" "));
+    }
+    let authors = entry.authors();
+    let authors = match authors.len() {
+        0 => None,
+        1 => Some(authors[0].clone()),
+        2 => Some(format!("{} and {}", authors[0], authors[1])),
+        n => Some(format!("{}, and {}", authors[..n - 1].join(", "), authors[n - 1])),
+    };
+    if let Some(authors) = authors {
+        children.push(Node::new_escaped_text(&format!("{}. ", authors)));
+    }
+    if let Some(title) = entry.field("title") {
+        children.push(Node::new_element(
+            "em",
+            HashMap::default(),
+            vec![Node::new_escaped_text(title)]
+        ));
+        children.push(Node::new_text(". "));
+    }
+    let details = vec!["journal", "booktitle", "publisher"]
+        .into_iter()
+        .filter_map(|x| entry.field(x))
+        .chain(year.or_else(|| entry.field("year")))
+        .collect::<Vec<_>>();
+    if !details.is_empty() {
+        children.push(Node::new_escaped_text(&format!("{}.", details.join(", "))));
+    }
+    if let Some(url) = entry.field("url") {
+        children.push(Node::new_text(" "));
+        children.push(Node::new_element(
+            "a",
+            html_attrs!{
+                "href": url,
+            },
+            vec![Node::new_escaped_text(url)]
+        ));
+    }
+    Node::new_element(
+        "li",
+        html_attrs!{
+            "id": {format!("bib-{}", entry.key)},
+        },
+        children
+    )
+}
+
+/// Expands `<cite key="...">` (comma separated for multiple entries) and
+/// `<bibliography>`, using the BibTeX file declared in the manifest. The
+/// style is taken from the `citation-style` attribute of the page's
+/// `<bibliography>` (`numeric` or `author-year`), defaulting to `numeric`.
+pub fn bibliography_pass(ctx: &MacroContext, html: &mut Node) {
+    fn find_style(node: &Node) -> Option<String> {
+        match node {
+            Node::Element(element) if element.tag == "bibliography" => {
+                element.attrs.get("citation-style").cloned()
+            }
+            Node::Element(element) => {
+                element.children.iter().find_map(find_style)
+            }
+            Node::Fragment(xs) => {
+                xs.iter().find_map(find_style)
+            }
+            Node::Text(_) => None
+        }
+    }
+    fn cite_keys(keys: &str) -> impl Iterator<Item=&str> {
+        keys.split(',').map(|key| key.trim()).filter(|key| !key.is_empty())
+    }
+    let style = find_style(html)
+        .map(|name| {
+            CitationStyle::new(&name).unwrap_or_else(|| {
+                ctx.warn(format!("unknown bibliography style {:?}", name));
+                CitationStyle::Numeric
+            })
+        })
+        .unwrap_or(CitationStyle::Numeric);
+    let bibliography = ctx.env.handles.access(|handles| handles.bibliography.clone());
+    // Keys in order of first citation.
+    let cited = Rc::new(RefCell::new(Vec::<String>::new()));
+    html.eval(Rc::new({
+        let cited = cited.clone();
+        move |node: &mut Node| {
+            if !node.is_tag("cite") {
+                return;
+            }
+            let keys = node.get_attr("key").unwrap_or_default();
+            let mut cited = cited.borrow_mut();
+            for key in cite_keys(&keys) {
+                if !cited.iter().any(|x| x == key) {
+                    cited.push(key.to_owned());
+                }
+            }
+        }
+    }));
+    let cited = cited
+        .borrow()
+        .iter()
+        .filter_map(|key| {
+            let entry = bibliography.get(key);
+            if entry.is_none() {
+                ctx.warn(format!("unknown citation key {:?}", key));
+            }
+            entry
+        })
+        .collect::<Vec<_>>();
+    let years = author_year_labels(&cited);
+    // Citation text and year (with any suffix) per key.
+    let labels = cited
+        .iter()
+        .enumerate()
+        .map(|(ix, entry)| {
+            let year = years.get(entry.key.as_str()).cloned();
+            let text = match style {
+                CitationStyle::Numeric => format!("{}", ix + 1),
+                CitationStyle::AuthorYear => match year.as_ref() {
+                    Some(year) => format!("{}, {}", author_year(entry).0, year),
+                    None => author_year(entry).0,
+                },
+            };
+            (entry.key.clone(), (text, year))
+        })
+        .collect::<HashMap<_, _>>();
+    let mut entries = cited
+        .iter()
+        .enumerate()
+        .map(|(ix, entry)| {
+            let label = match style {
+                CitationStyle::Numeric => Some(format!("[{}]", ix + 1)),
+                CitationStyle::AuthorYear => None,
+            };
+            let year = labels[&entry.key].1.as_deref();
+            (entry.surnames(), year, bibliography_entry(entry, label, year))
+        })
+        .collect::<Vec<_>>();
+    if style == CitationStyle::AuthorYear {
+        entries.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    }
+    let entries = entries
+        .into_iter()
+        .map(|(_, _, entry)| entry)
+        .collect::<Vec<_>>();
+    let labels = Rc::new(labels);
+    html.eval(Rc::new(move |node: &mut Node| {
+        if node.is_tag("cite") {
+            let keys = match node.get_attr("key") {
+                Some(keys) => keys,
+                None => return,
+            };
+            let (open, sep, close) = match style {
+                CitationStyle::Numeric => ("[", ", ", "]"),
+                CitationStyle::AuthorYear => ("(", "; ", ")"),
+            };
+            let mut children = vec![Node::new_text(open)];
+            for (ix, key) in cite_keys(&keys).enumerate() {
+                if ix > 0 {
+                    children.push(Node::new_text(sep));
+                }
+                children.push(match labels.get(key) {
+                    Some((text, _)) => Node::new_element(
+                        "a",
+                        html_attrs!{
+                            "href": {format!("#bib-{}", key)},
+                        },
+                        vec![Node::new_escaped_text(text)]
+                    ),
+                    None => Node::new_text("?"),
+                });
+            }
+            children.push(Node::new_text(close));
+            *node = Node::new_element(
+                "cite",
+                html_attrs!{
+                    "macro": "cite",
+                },
+                children
+            );
+        }
+        if node.is_tag("bibliography") {
+            let mut attrs = node.get_attributes();
+            attrs.insert(String::from("macro"), String::from("bibliography"));
+            *node = Node::new_element(
+                "ul",
+                attrs,
+                entries.clone()
+            );
+        }
+    }));
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// FOOTNOTES & SIDENOTES
+///////////////////////////////////////////////////////////////////////////////
+
+/// `<footnote>`, `<sidenote>` and `<marginnote>` are expanded in the bottom-up
+/// pass like any other tag macro, but since an `<include>`'d file is expanded
+/// before it's spliced into the page, numbering is deferred to
+/// `notes_pass`, which sees the final document.
+pub fn notes_tags() -> Vec<TagMacro> {
+    vec![
+        TagMacro {
+            tag: String::from("footnote"),
+            callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
+                *node = Node::new_element(
+                    "sup",
+                    html_attrs!{
+                        "macro": "footnote",
+                    },
+                    vec![
+                        Node::new_element("a", HashMap::default(), Vec::new()),
+                        Node::new_element(
+                            "span",
+                            html_attrs!{
+                                "footnote-content": "",
+                            },
+                            node.get_children(),
+                        ),
+                    ],
+                );
+            })),
+        },
+        TagMacro {
+            tag: String::from("sidenote"),
+            callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
+                *node = margin_note("sidenote", node.get_children());
+            })),
+        },
+        TagMacro {
+            tag: String::from("marginnote"),
+            callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
+                *node = margin_note("marginnote", node.get_children());
+            })),
+        },
+    ]
+}
+
+/// Tufte-style markup; the checkbox lets narrow screens toggle the note.
+fn margin_note(kind: &str, children: Vec<Node>) -> Node {
+    Node::new_element(
+        "span",
+        html_attrs!{
+            "macro": kind,
+        },
+        vec![
+            Node::new_element(
+                "label",
+                html_attrs!{
+                    "margin-toggle": "",
+                },
+                Vec::new(),
+            ),
+            Node::new_element(
+                "input",
+                html_attrs!{
+                    "type": "checkbox",
+                    "margin-toggle": "",
+                },
+                Vec::new(),
+            ),
+            Node::new_element(
+                "span",
+                html_attrs!{
+                    "note-content": "",
+                },
+                children,
+            ),
+        ],
+    )
+}
+
+/// Numbers the output of `notes_tags` in document order. Footnote contents are
+/// moved to a list at the end of the enclosing `<section>`, or else the end of
+/// the `<body>` (or document).
+pub fn notes_pass(env: &Env, html: &mut Node) {
+    #[derive(Default)]
+    struct Counters {
+        footnotes: usize,
+        sidenotes: usize,
+        marginnotes: usize,
+    }
+    fn footnote_list(notes: Vec<(usize, Vec<Node>)>) -> Node {
+        let items = notes
+            .into_iter()
+            .map(|(n, mut children)| {
+                children.push(Node::new_text(" "));
+                children.push(Node::new_element(
+                    "a",
+                    html_attrs!{
+                        "href": {format!("#fnref-{}", n)},
+                        "footnote-backref": "",
+                    },
+                    vec![Node::new_text("↩")],
+                ));
+                Node::new_element(
+                    "li",
+                    html_attrs!{
+                        "id": {format!("fn-{}", n)},
+                        "value": n,
+                    },
+                    children,
+                )
+            })
+            .collect::<Vec<_>>();
+        Node::new_element(
+            "ol",
+            html_attrs!{
+                "macro": "footnotes",
+            },
+            items,
+        )
+    }
+    fn number_margin_note(node: &mut Node, id: &str, label: &str) {
+        if let Node::Element(element) = node {
+            for child in element.children.iter_mut() {
+                if child.is_tag("label") {
+                    child.set_attr("for", id.to_owned());
+                    child.replace_children(vec![Node::new_text(label)]);
+                }
+                if child.is_tag("input") {
+                    child.set_attr("id", id.to_owned());
+                }
+            }
+        }
+    }
+    fn runner(node: &mut Node, counters: &mut Counters, notes: &mut Vec<(usize, Vec<Node>)>) {
+        let mut section_notes = Vec::new();
+        let is_section = node.is_tag("section");
+        let notes = if is_section {&mut section_notes} else {notes};
+        let children = match node {
+            Node::Element(element) => &mut element.children,
+            Node::Fragment(xs) => xs,
+            Node::Text(_) => return,
+        };
+        for child in children.iter_mut() {
+            if child.has_attr_value("macro", "footnote") {
+                counters.footnotes += 1;
+                let n = counters.footnotes;
+                let contents = child
+                    .get_children()
+                    .into_iter()
+                    .find(|x| x.has_attr("footnote-content"))
+                    .map(|x| x.get_children())
+                    .unwrap_or_default();
+                notes.push((n, contents));
+                child.set_attr("id", format!("fnref-{}", n));
+                child.replace_children(vec![Node::new_element(
+                    "a",
+                    html_attrs!{
+                        "href": {format!("#fn-{}", n)},
+                    },
+                    vec![Node::new_text(&n.to_string())],
+                )]);
+            } else if child.has_attr_value("macro", "sidenote") {
+                counters.sidenotes += 1;
+                let n = counters.sidenotes;
+                number_margin_note(child, &format!("sn-{}", n), &n.to_string());
+            } else if child.has_attr_value("macro", "marginnote") {
+                counters.marginnotes += 1;
+                let n = counters.marginnotes;
+                number_margin_note(child, &format!("mn-{}", n), "⊕");
+            } else {
+                let is_body = child.is_tag("body");
+                if is_body {
+                    let mut body_notes = Vec::new();
+                    runner(child, counters, &mut body_notes);
+                    if !body_notes.is_empty() {
+                        child.append_children(vec![footnote_list(body_notes)]);
+                    }
+                } else {
+                    runner(child, counters, notes);
+                }
+            }
+        }
+        if is_section && !section_notes.is_empty() {
+            node.append_children(vec![footnote_list(section_notes)]);
+        }
+    }
+    let mut counters = Counters::default();
+    let mut notes = Vec::new();
+    runner(html, &mut counters, &mut notes);
+    if !notes.is_empty() {
+        let list = footnote_list(notes);
+        match html {
+            Node::Fragment(xs) => xs.push(list),
+            node => node.append_children(vec![list]),
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// THEOREM-LIKE ENVIRONMENTS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Tag names and their display names.
+pub const THEOREM_ENVIRONMENTS: &[(&str, &str)] = &[
+    ("definition", "Definition"),
+    ("theorem", "Theorem"),
+    ("lemma", "Lemma"),
+    ("proof", "Proof"),
+    ("example", "Example"),
+];
+
+fn environment_name(tag: &str) -> &str {
+    THEOREM_ENVIRONMENTS
+        .iter()
+        .find(|(x, _)| *x == tag)
+        .map(|(_, name)| *name)
+        .unwrap_or(tag)
+}
+
+/// Like `note_tag`, but with a header e.g. “Theorem 2 (Pythagoras).” The
+/// number is filled in by `numbering_pass`. Each environment has its own
+/// counter unless given e.g. `counter="theorem"`; `proof` (and anything marked
+/// `unnumbered`) isn't numbered.
+pub fn theorem_tags() -> Vec<TagMacro> {
+    THEOREM_ENVIRONMENTS
+        .iter()
+        .map(|(tag, name)| {
+            let tag = String::from(*tag);
+            let name = String::from(*name);
+            TagMacro {
+                tag: tag.clone(),
+                callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+                    let mut header = vec![Node::new_element(
+                        "span",
+                        html_attrs!{
+                            "env-name": "",
+                        },
+                        vec![Node::new_text(&name)],
+                    )];
+                    // Moved into the header, rather than left as a tooltip.
+                    if let Some(title) = node.remove_attr("title") {
+                        header.push(Node::new_text(" "));
+                        header.push(Node::new_element(
+                            "span",
+                            html_attrs!{
+                                "env-title": "",
+                            },
+                            vec![Node::new_text(&format!("({})", title))],
+                        ));
+                    }
+                    header.push(Node::new_text("."));
+                    let mut children = vec![Node::new_element(
+                        "div",
+                        html_attrs!{
+                            "env-header": "",
+                        },
+                        header,
+                    )];
+                    children.append(&mut node.get_children());
+                    if tag == "proof" {
+                        children.push(Node::new_element(
+                            "span",
+                            html_attrs!{
+                                "qed": "",
+                            },
+                            vec![Node::new_text("∎")],
+                        ));
+                    }
+                    node.replace_children(children);
+                    node.set_tag("div");
+                    node.set_attr("macro", tag.clone());
+                    node.set_attr("theorem-env", String::new());
+                })),
+            }
+        })
+        .collect()
+}
+
+/// Inserts the number after the environment's name in its header.
+fn theorem_env_number(node: &mut Node, number: &str) {
+    if let Node::Element(element) = node {
+        for child in element.children.iter_mut() {
+            if !child.has_attr("env-header") {
+                continue;
+            }
+            let mut header = child.get_children();
+            header.insert(1, Node::new_text(" "));
+            header.insert(2, Node::new_element(
+                "span",
+                html_attrs!{
+                    "env-number": "",
+                },
+                vec![Node::new_text(number)],
+            ));
+            child.replace_children(header);
+            return;
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// GLOSSARY & INDEX
+///////////////////////////////////////////////////////////////////////////////
+
+/// A `<term>` definition or `<index-entry>` marker, as collected by `SitePage`.
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub id: String,
+    pub term: String,
+    /// Only set for `<term>`s.
+    pub definition: Option<String>,
+}
+
+impl IndexEntry {
+    /// Returns `(terms, index_entries)`; must run after `index_pass`.
+    pub fn collect(node: &Node) -> (Vec<IndexEntry>, Vec<IndexEntry>) {
+        fn runner(node: &Node, terms: &mut Vec<IndexEntry>, entries: &mut Vec<IndexEntry>) {
+            if node.has_attr_value("macro", "term") {
+                let text = node.get_children_as_text().join(" ");
+                if let Some(id) = node.get_attr("id") {
+                    terms.push(IndexEntry {
+                        id,
+                        term: text.trim().to_owned(),
+                        definition: node.get_attr("definition"),
+                    });
+                }
+            }
+            if node.has_attr_value("macro", "index-entry") {
+                let id = node.get_attr("id");
+                let term = node.get_attr("index-term");
+                if let (Some(id), Some(term)) = (id, term) {
+                    entries.push(IndexEntry {
+                        id,
+                        term,
+                        definition: None,
+                    });
+                }
+            }
+            match node {
+                Node::Element(element) => {
+                    for child in element.children.iter() {
+                        runner(child, terms, entries);
+                    }
+                }
+                Node::Fragment(xs) => {
+                    for child in xs.iter() {
+                        runner(child, terms, entries);
+                    }
+                }
+                Node::Text(_) => {}
+            }
+        }
+        let mut terms = Vec::new();
+        let mut entries = Vec::new();
+        runner(node, &mut terms, &mut entries);
+        (terms, entries)
+    }
+}
+
+/// `<term id="..." definition="...">` renders as a `<dfn>`, and
+/// `<index-entry>` as an anchor for `<book-index>`. The latter is invisible
+/// when given a `term` attribute, otherwise its contents are the term.
+pub fn index_tags() -> Vec<TagMacro> {
+    vec![
+        TagMacro {
+            tag: String::from("term"),
+            callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
+                node.set_tag("dfn");
+                node.set_attr("macro", String::from("term"));
+            })),
+        },
+        TagMacro {
+            tag: String::from("index-entry"),
+            callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
+                let (term, children) = match node.get_attr("term") {
+                    Some(term) => (term, Vec::new()),
+                    None => (
+                        node.get_children_as_text().join(" ").trim().to_owned(),
+                        node.get_children(),
+                    ),
+                };
+                *node = Node::new_element(
+                    "span",
+                    html_attrs!{
+                        "macro": "index-entry",
+                        "index-term": term,
+                    },
+                    children,
+                );
+            })),
+        },
+    ]
+}
+
+/// Gives every `<index-entry>` on the page a unique `id`.
+pub fn index_pass(env: &Env, html: &mut Node) {
+    let counter = Rc::new(RefCell::new(0));
+    html.eval(Rc::new(move |node: &mut Node| {
+        if node.has_attr_value("macro", "index-entry") && node.get_attr("id").is_none() {
+            let mut counter = counter.borrow_mut();
+            *counter += 1;
+            node.set_attr("id", format!("index-{}", counter));
+        }
+    }));
+}
+
+/// Sorts by term, ignoring case.
+fn sort_entries<'a>(entries: &mut Vec<(&'a SitePage, &'a IndexEntry)>) {
+    entries.sort_by_key(|(page, entry)| {
+        (entry.term.to_lowercase(), page.route.clone())
+    });
+}
+
+/// Expands `<glossary>` and `<book-index>` using the terms and index entries
+/// of every page in `Env.io_paths`.
+pub fn glossary_tags(env: &Env, site_pages: &[SitePage], html: &mut Node) {
+    let base_url = env.base_url
+        .as_ref()
+        .map(|x| x.strip_suffix("/").unwrap_or(x).to_owned())
+        .unwrap_or_default();
+    let href = move |page: &SitePage, entry: &IndexEntry| {
+        format!("{}{}#{}", base_url, page.route, entry.id)
+    };
+    let mut terms = site_pages
+        .iter()
+        .flat_map(|page| page.terms.iter().map(move |x| (page, x)))
+        .collect::<Vec<_>>();
+    sort_entries(&mut terms);
+    let glossary = terms
+        .iter()
+        .flat_map(|(page, term)| {
+            let mut dd = Vec::new();
+            if let Some(definition) = term.definition.as_ref() {
+                dd.push(Node::new_text(definition));
+            }
+            vec![
+                Node::new_element(
+                    "dt",
+                    HashMap::default(),
+                    vec![Node::new_element(
+                        "a",
+                        html_attrs!{
+                            "href": {href(page, term)},
+                        },
+                        vec![Node::new_text(&term.term)],
+                    )],
+                ),
+                Node::new_element("dd", HashMap::default(), dd),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut entries = site_pages
+        .iter()
+        .flat_map(|page| {
+            page.terms
+                .iter()
+                .chain(page.index_entries.iter())
+                .map(move |x| (page, x))
+        })
+        .collect::<Vec<_>>();
+    sort_entries(&mut entries);
+    let mut index = Vec::<(String, Vec<Node>)>::new();
+    for (page, entry) in entries {
+        let link = Node::new_element(
+            "a",
+            html_attrs!{
+                "href": {href(page, entry)},
+            },
+            vec![Node::new_text(&page.title)],
+        );
+        match index.last_mut() {
+            Some((term, links)) if term.to_lowercase() == entry.term.to_lowercase() => {
+                links.push(Node::new_text(", "));
+                links.push(link);
+            }
+            _ => index.push((entry.term.clone(), vec![link])),
+        }
+    }
+    let index = index
+        .into_iter()
+        .map(|(term, mut links)| {
+            let mut children = vec![
+                Node::new_element(
+                    "span",
+                    html_attrs!{
+                        "index-term": "",
+                    },
+                    vec![Node::new_text(&term)],
+                ),
+                Node::new_text(" "),
+            ];
+            children.append(&mut links);
+            Node::new_element("li", HashMap::default(), children)
+        })
+        .collect::<Vec<_>>();
+    html.eval(Rc::new(move |node: &mut Node| {
+        let (tag, children) = if node.is_tag("glossary") {
+            ("dl", glossary.clone())
+        } else if node.is_tag("book-index") {
+            ("ul", index.clone())
+        } else {
+            return;
+        };
+        let mut attrs = node.get_attributes();
+        attrs.insert(String::from("macro"), node.tag().unwrap());
+        *node = Node::new_element(tag, attrs, children);
+    }));
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// USER-DEFINED COMPONENTS
+///////////////////////////////////////////////////////////////////////////////
+
+/// A `<define tag="...">` from the components directory. Its children are
+/// expanded like an `<include>`'d template.
+#[derive(Debug, Clone)]
+pub struct Component {
+    pub tag: String,
+    /// Relative paths in the template are resolved against this.
+    pub dir: PathBuf,
+    pub template: Node,
+}
+
+/// Loads every `<define>` in the `*.html` files under `dir`. Problems are
+/// reported against the component file, and printed with every build.
+pub fn load_components(dir: &Path, diagnostics: &mut Vec<Diagnostic>) -> Vec<Component> {
+    let pattern = dir.join("**/*.html");
+    let files = crate::frontend::io::expand_globs(vec![
+        pattern.to_str().unwrap().to_owned()
+    ]);
+    let mut components = Vec::<Component>::new();
+    for path in files {
+        let source = match crate::frontend::io::try_load_text_file(&path) {
+            Ok(x) => x,
+            Err(_) => {
+                diagnostics.push(Diagnostic::warning(&path, "failed to read component file"));
+                continue;
+            }
+        };
+        let defines = Rc::new(RefCell::new(Vec::<Node>::new()));
+        Node::parse_string(source).eval(Rc::new({
+            let defines = defines.clone();
+            move |node: &mut Node| {
+                if node.is_tag("define") {
+                    defines.borrow_mut().push(node.clone());
+                }
+            }
+        }));
+        let defines = defines.borrow().clone();
+        for define in defines {
+            let tag = match define.get_attr("tag") {
+                Some(tag) => tag.to_lowercase(),
+                None => {
+                    diagnostics.push(Diagnostic::warning(&path, "<define> without a tag"));
+                    continue;
+                }
+            };
+            if components.iter().any(|x| x.tag == tag) {
+                diagnostics.push(Diagnostic::warning(
+                    &path,
+                    format!("ignoring duplicate component <{}>", tag),
+                ));
+                continue;
+            }
+            components.push(Component {
+                tag,
+                dir: path.parent().unwrap().to_owned(),
+                template: Node::Fragment(define.get_children()),
+            });
+        }
+    }
+    components
+}
+
+/// Built-in macros take precedence over components with the same tag.
+pub fn component_tags(components: &[Component], builtins: &[TagMacro]) -> Vec<TagMacro> {
+    components
+        .iter()
+        .filter(|component| !builtins.iter().any(|x| x.tag == component.tag))
+        .cloned()
+        .map(|component| {
+            let tag = component.tag.clone();
+            let callback = Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+                let name = format!("<{}>", component.tag);
+                expand_template(ctx, name, &component.dir, component.template.clone(), node);
+            });
+            TagMacro {
+                tag,
+                callback: MacroCallbackMut(callback),
+            }
+        })
+        .collect()
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ATTRIBUTE MACROS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Macros triggered by an attribute on any element, which is removed once
+/// expanded; see also `markdown_attr`, which runs before the element's
+/// children are expanded.
+pub fn attr_macros() -> Vec<AttrMacro> {
+    vec![
+        typography_attr(),
+        numbered_attr(),
+    ]
+}
+
+/// Removes the indentation common to every non-blank line, so indented
+/// markdown in an HTML file isn't mistaken for code blocks.
+fn dedent(source: &str) -> String {
+    let indent = source
+        .lines()
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| x.len() - x.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    source
+        .lines()
+        .map(|x| x.get(indent..).unwrap_or(x.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Replaces code and math (at any depth) with placeholders, so their
+/// contents aren't parsed as markdown.
+fn stash_verbatim(node: &mut Node, stash: &mut Vec<Node>) {
+    match node {
+        Node::Element(element) if is_verbatim(element) => {
+            let placeholder = Node::new_text(&format!("\u{E000}{}\u{E001}", stash.len()));
+            stash.push(std::mem::replace(node, placeholder));
+        }
+        Node::Element(element) => {
+            for child in element.children.iter_mut() {
+                stash_verbatim(child, stash);
+            }
+        }
+        Node::Fragment(xs) => {
+            for x in xs.iter_mut() {
+                stash_verbatim(x, stash);
+            }
+        }
+        Node::Text(_) => {}
+    }
+}
+
+/// Undoes `stash_verbatim`. A placeholder on its own in a paragraph, e.g. for
+/// an `<equation>`, replaces the paragraph.
+fn unstash_verbatim(node: &mut Node, stash: &[Node]) {
+    fn parse(text: &str) -> Option<usize> {
+        text.strip_prefix('\u{E000}')?.strip_suffix('\u{E001}')?.parse().ok()
+    }
+    match node {
+        Node::Element(element) => {
+            if element.tag == "p" {
+                if let [Node::Text(text)] = &element.children[..] {
+                    if let Some(node_ref) = parse(text.trim()).and_then(|ix| stash.get(ix)) {
+                        *node = node_ref.clone();
+                        return;
+                    }
+                }
+            }
+            for child in element.children.iter_mut() {
+                unstash_verbatim(child, stash);
+            }
+        }
+        Node::Fragment(xs) => {
+            for x in xs.iter_mut() {
+                unstash_verbatim(x, stash);
+            }
+        }
+        Node::Text(text) if text.contains('\u{E000}') => {
+            let mut parts = Vec::new();
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find('\u{E000}') {
+                let end = match rest[start..].find('\u{E001}') {
+                    Some(end) => start + end + '\u{E001}'.len_utf8(),
+                    None => break,
+                };
+                match parse(&rest[start..end]).and_then(|ix| stash.get(ix)) {
+                    Some(stashed) => {
+                        if start > 0 {
+                            parts.push(Node::new_text(&rest[..start]));
+                        }
+                        parts.push(stashed.clone());
+                    }
+                    None => parts.push(Node::new_text(&rest[..end])),
+                }
+                rest = &rest[end..];
+            }
+            if !rest.is_empty() {
+                parts.push(Node::new_text(rest));
+            }
+            *node = Node::Fragment(parts);
+        }
+        Node::Text(_) => {}
+    }
+}
+
+/// Compiles the source of `children` as markdown, leaving code and math as is.
+fn markdown_children(mut children: Vec<Node>) -> Vec<Node> {
+    let mut stash = Vec::new();
+    for child in children.iter_mut() {
+        stash_verbatim(child, &mut stash);
+    }
+    let source = children
+        .iter()
+        .map(|x| x.to_html_str(0))
+        .collect::<String>();
+    let mut html = Node::Fragment(compile_markdown(dedent(&source)).unwrap_contents("div"));
+    unstash_verbatim(&mut html, &stash);
+    html.into_fragment()
+}
+
+/// `<div ss:markdown>` compiles the element's contents as markdown. Runs
+/// before the contents are expanded (see `PreOrder`), so they're still the
+/// source as written, e.g. `<tex>` rather than the HTML it expands to.
+pub fn markdown_attr() -> AttrMacro {
+    AttrMacro {
+        attr: String::from("ss:markdown"),
+        callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
+            node.remove_attr("ss:markdown");
+            let children = match node {
+                Node::Element(element) => std::mem::take(&mut element.children),
+                _ => Vec::new(),
+            };
+            node.replace_children(markdown_children(children));
+        })),
+    }
+}
+
+/// Code and math, whose text is left as is by `ss:typography` and shortcodes.
+fn is_verbatim(element: &Element) -> bool {
+    let tags = [
+        "code", "pre", "kbd", "samp", "script", "style", "math", "tex",
+        "texblock", "equation",
+    ];
+    tags.contains(&element.tag.as_str()) || element.attrs.contains_key("latex")
+}
+
+/// Smart quotes, en/em dashes (`--`/`---`) and ellipses, outside of code and
+/// math. `prev` is the character before `text`, if any, e.g. at the end of
+/// the previous text node, and is updated to the last character of `text`.
+fn typography(text: &str, prev: &mut Option<char>) -> String {
+    let text = text
+        .replace("---", "\u{2014}")
+        .replace("--", "\u{2013}")
+        .replace("...", "\u{2026}");
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        let opening = prev
+            .map(|x| x.is_whitespace() || "([{\u{2014}\u{2013}".contains(x))
+            .unwrap_or(true);
+        match c {
+            '"' if opening => out.push('\u{201C}'),
+            '"' => out.push('\u{201D}'),
+            '\'' if opening => out.push('\u{2018}'),
+            '\'' => out.push('\u{2019}'),
+            c => out.push(c),
+        }
+        *prev = Some(c);
+    }
+    out
+}
+
+/// Applies `typography` to the text in `node`, in document order, so quotes
+/// around inline elements (e.g. `"<em>x</em>"`) are paired up. Block elements
+/// start afresh.
+fn typography_node(node: &mut Node, prev: &mut Option<char>) {
+    let inline = node.is_inline_node();
+    match node {
+        Node::Element(element) if is_verbatim(element) => {
+            // Code and math are left as is, but count as text.
+            let last = node.get_children_as_text().concat().chars().last();
+            *prev = if inline {last.or(*prev)} else {None};
+        }
+        Node::Element(element) => {
+            let mut inner = if inline {*prev} else {None};
+            for child in element.children.iter_mut() {
+                typography_node(child, &mut inner);
+            }
+            *prev = if inline {inner} else {None};
+        }
+        Node::Fragment(xs) => {
+            for x in xs.iter_mut() {
+                typography_node(x, prev);
+            }
+        }
+        Node::Text(text) => {
+            *text = typography(text, prev);
+        }
+    }
+}
+
+/// `<p ss:typography>` applies `typography` to the element's text.
+pub fn typography_attr() -> AttrMacro {
+    AttrMacro {
+        attr: String::from("ss:typography"),
+        callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
+            node.remove_attr("ss:typography");
+            typography_node(node, &mut None);
+        })),
+    }
+}
+
+/// `<section ss:numbered>` prefixes the headings in the element with
+/// hierarchical numbers, e.g. “1.2”, relative to the highest level heading.
+pub fn numbered_attr() -> AttrMacro {
+    AttrMacro {
+        attr: String::from("ss:numbered"),
+        callback: MacroCallbackMut(Arc::new(|ctx: &mut MacroContext, node: &mut Node| {
+            node.remove_attr("ss:numbered");
+            let selector = "h1, h2, h3, h4, h5, h6";
+            // Headings are a valid selector, hence the `unwrap`s.
+            let levels = node
+                .select(selector)
+                .unwrap()
+                .iter()
+                .filter_map(|x| x.tag()?[1..].parse::<usize>().ok())
+                .collect::<Vec<_>>();
+            let top = levels.iter().cloned().min().unwrap_or(1);
+            let mut counters = Vec::<usize>::new();
+            let mut numbers = levels
+                .iter()
+                .map(|level| {
+                    counters.resize(level - top + 1, 0);
+                    *counters.last_mut().unwrap() += 1;
+                    counters
+                        .iter()
+                        .map(|x| x.to_string())
+                        .collect::<Vec<_>>()
+                        .join(".")
+                })
+                .collect::<Vec<_>>();
+            // Matches are visited in reverse document order.
+            node.select_mut(selector, |x| {
+                let number = numbers.pop().unwrap_or_default();
+                let mut children = vec![
+                    Node::new_element(
+                        "span",
+                        html_attrs!{
+                            "heading-number": "",
+                        },
+                        vec![Node::new_text(&number)],
+                    ),
+                    Node::new_text(" "),
+                ];
+                children.extend(x.get_children());
+                x.replace_children(children);
+            }).unwrap();
+        })),
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// SHORTCODES
+///////////////////////////////////////////////////////////////////////////////
+
+/// Attributes given the argument of `{{tag:arg}}`, for macros that take one;
+/// otherwise it becomes the element's text.
+const SHORTCODE_ATTRS: &[(&str, &str)] = &[
+    ("ref", "to"),
+    ("cite", "key"),
+    ("include", "src"),
+];
+
+/// `source` is the shortcode as written, left as is if it can't be expanded.
+#[derive(Debug, Clone, PartialEq)]
+enum Shortcode {
+    Text(String),
+    /// `{{page.key}}`
+    Page {key: String, source: String},
+    /// `{{tag:arg}}` or `@tag{arg}`
+    Call {tag: String, arg: String, source: String},
+}
+
+fn is_shortcode_ident(x: &str) -> bool {
+    !x.is_empty() && x.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The index in `text` of the `close` matching an opening brace just before
+/// it, skipping over nested `{...}`.
+fn find_closing(text: &str, close: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    for (ix, c) in text.char_indices() {
+        if depth == 0 && text[ix..].starts_with(close) {
+            return Some(ix);
+        }
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.checked_sub(1)?,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits `text` into literal text and shortcodes. Anything that doesn't parse
+/// as a shortcode is left as text.
+fn parse_shortcodes(text: &str) -> Vec<Shortcode> {
+    let mut out = Vec::<Shortcode>::new();
+    let mut literal = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let shortcode = if let Some(after) = rest.strip_prefix("{{") {
+            find_closing(after, "}}").and_then(|end| {
+                let source = rest[..end + 4].to_owned();
+                let inner = after[..end].trim();
+                let shortcode = if let Some(key) = inner.strip_prefix("page.") {
+                    Some(Shortcode::Page {key: key.to_owned(), source})
+                        .filter(|_| key.split('.').all(is_shortcode_ident))
+                } else {
+                    let ix = inner.find(':')?;
+                    let tag = inner[..ix].trim();
+                    let arg = inner[ix + 1..].trim();
+                    Some(Shortcode::Call {tag: tag.to_owned(), arg: arg.to_owned(), source})
+                        .filter(|_| is_shortcode_ident(tag))
+                };
+                Some((shortcode?, end + 4))
+            })
+        } else if c == '@' && !literal.ends_with(|x: char| x.is_alphanumeric()) {
+            rest.find('{').and_then(|open| {
+                let tag = &rest[1..open];
+                let close = open + 1 + find_closing(&rest[open + 1..], "}")?;
+                let shortcode = Shortcode::Call {
+                    tag: tag.to_owned(),
+                    arg: rest[open + 1..close].trim().to_owned(),
+                    source: rest[..close + 1].to_owned(),
+                };
+                Some((shortcode, close + 1)).filter(|_| is_shortcode_ident(tag))
+            })
+        } else {
+            None
+        };
+        match shortcode {
+            Some((shortcode, len)) => {
+                if !literal.is_empty() {
+                    out.push(Shortcode::Text(std::mem::take(&mut literal)));
+                }
+                out.push(shortcode);
+                rest = &rest[len..];
+            }
+            None => {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if !literal.is_empty() {
+        out.push(Shortcode::Text(literal));
+    }
+    out
+}
+
+/// Inline syntax for text, enabled by `shortcodes = true` in the manifest's
+/// `[project]` table:
+///
+/// - `{{tag:arg}}` and `@tag{arg}` become `<tag>`, expanded by the registered
+///   macros, e.g. `{{ref:eq-1}}` is `<ref to="eq-1"></ref>` and
+///   `@cite{knuth84}` is `<cite key="knuth84"></cite>`. Tags without a macro
+///   are left as text.
+/// - `{{page.title}}`, `{{page.route}}`, or any other key of the page's
+///   `MacroContext::metadata`.
+///
+/// Runs before the other `Document` macros, so the page is fully expanded
+/// (including `<include>`'d templates), and outside of code and math.
+pub struct Shortcodes;
+
+impl Macro for Shortcodes {
+    fn name(&self) -> &str {
+        "shortcodes"
+    }
+    fn phase(&self) -> Phase {
+        Phase::Document
+    }
+    fn before(&self) -> Vec<String> {
+        pass_macros()
+            .into_iter()
+            .filter(|x| x.phase == Phase::Document)
+            .map(|x| x.name.to_owned())
+            .collect()
+    }
+    fn expand(&self, ctx: &mut MacroContext, html: &mut Node) -> Result<(), MacroError> {
+        let page = {
+            // As in `SitePage::new`, though headings don't have ids yet.
+            let route = ctx.env.io_path.route();
+            let title = html
+                .select("h1")
+                .unwrap()
+                .first()
+                .map(|x| x.get_children_as_text().join(" "))
+                .or_else(|| html.select("title").unwrap().first()?.get_text_contents())
+                .unwrap_or_else(|| route.clone());
+            let mut page = HashMap::new();
+            page.insert(String::from("title"), title);
+            page.insert(String::from("route"), route);
+            page.extend(ctx.metadata().access(|x| x.clone()));
+            page
+        };
+        for_each_prose_text(html, &mut |node| {
+            let shortcodes = match node {
+                Node::Text(text) => parse_shortcodes(text),
+                _ => return,
+            };
+            if shortcodes.iter().all(|x| matches!(x, Shortcode::Text(_))) {
+                return;
+            }
+            let nodes = shortcodes
+                .into_iter()
+                .map(|shortcode| expand_shortcode(ctx, &page, shortcode))
+                .collect::<Vec<_>>();
+            *node = Node::Fragment(nodes);
+        });
+        Ok(())
+    }
+}
+
+/// Calls `f` on each text node in `node`, outside of code and math.
+fn for_each_prose_text(node: &mut Node, f: &mut dyn FnMut(&mut Node)) {
+    match node {
+        Node::Element(element) => {
+            if is_verbatim(element) {
+                return;
+            }
+            for child in element.children.iter_mut() {
+                for_each_prose_text(child, f);
+            }
+        }
+        Node::Fragment(xs) => {
+            for x in xs.iter_mut() {
+                for_each_prose_text(x, f);
+            }
+        }
+        Node::Text(_) => f(node),
+    }
+}
+
+fn expand_shortcode(
+    ctx: &mut MacroContext,
+    page: &HashMap<String, String>,
+    shortcode: Shortcode,
+) -> Node {
+    match shortcode {
+        Shortcode::Text(text) => Node::Text(text),
+        Shortcode::Page {key, source} => match page.get(&key) {
+            Some(value) => Node::new_escaped_text(value),
+            None => {
+                ctx.warn(format!("unknown shortcode {}", source));
+                Node::Text(source)
+            }
+        },
+        Shortcode::Call {tag, arg, source} => {
+            let attr = SHORTCODE_ATTRS
+                .iter()
+                .find(|(x, _)| *x == tag)
+                .map(|(_, attr)| *attr);
+            if attr.is_none() && !ctx.env.macro_system.registry.has_tag(&tag) {
+                return Node::Text(source);
+            }
+            let mut node = match attr {
+                Some(attr) => {
+                    let mut attrs = HashMap::new();
+                    attrs.insert(attr.to_owned(), arg);
+                    Node::new_element(&tag, attrs, vec![])
+                }
+                None => Node::new_element(&tag, HashMap::new(), vec![Node::Text(arg)]),
+            };
+            crate::frontend::apply_macros(ctx.env, &mut node);
+            node
+        }
+    }
+}
+
+pub fn compile_markdown(source: String) -> Node {
+    let html_str = {
+        use comrak::{markdown_to_html, ComrakOptions};
+        let mut options = ComrakOptions::default();
+        options.render.unsafe_ = true;
+        options.render.unsafe_ = true;
+        let out = markdown_to_html(&source, &options);
+        out
+    };
+    Node::parse_str(&html_str)
+}
+
+pub fn markdown_tag() -> TagMacro {
+    TagMacro {
+        tag: String::from("markdown"),
+        callback: MacroCallbackMut(Arc::new(move |ctx: &mut MacroContext, node: &mut Node| {
+            let env = ctx.env;
+            node.get_attr("src")
+                .map(|src| -> PathBuf {
+                    env.current_dir.join(src)
+                })
+                .and_then(|src| crate::frontend::io::try_load_text_file(src).ok())
+                .map(|markdown| compile_markdown(markdown))
+                .map(|html| html.unwrap_contents("div"))
+                .map(|html| {
+                    *node = Node::Fragment(html);
+                });
+        })),
+    }
+}
+
+/// Macros run in `Phase::Expand`; see also `include_tag` and
+/// `component_tags`, which run in `Phase::Include`.
+pub fn tag_macros() -> Vec<TagMacro> {
+    let mut items = vec![
+        subscript_deps(),
+        link_tag(),
+        page_nav_tag(),
+        breadcrumbs_tag(),
+        layout_tag(),
+        asset_glob_tag(),
+        img_tag(),
+        markdown_tag(),
+        note_tag(),
+        figure_box_tag(),
+    ];
+    items.append(&mut latex_suit());
+    items.append(&mut notes_tags());
+    items.append(&mut theorem_tags());
+    items.append(&mut index_tags());
+    items
+}
+
+/// Whole-page passes, in `Phase::Document` (once the page has been expanded)
+/// and `Phase::Finalize` (once every page has been through `Phase::Document`).
+pub fn pass_macros() -> Vec<PassMacro> {
+    vec![
+        PassMacro {
+            name: "notes",
+            phase: Phase::Document,
+            after: Vec::new(),
+            pass: |ctx, html| notes_pass(ctx.env, html),
+        },
+        PassMacro {
+            name: "index",
+            phase: Phase::Document,
+            after: Vec::new(),
+            pass: |ctx, html| index_pass(ctx.env, html),
+        },
+        PassMacro {
+            name: "numbering",
+            phase: Phase::Document,
+            after: Vec::new(),
+            pass: |ctx, html| numbering_pass(ctx.env, html),
+        },
+        PassMacro {
+            name: "list-of-figures",
+            phase: Phase::Document,
+            after: vec!["numbering"],
+            pass: |ctx, html| list_of_figures_tag(ctx.env, html),
+        },
+        PassMacro {
+            name: "bibliography",
+            phase: Phase::Document,
+            after: Vec::new(),
+            pass: |ctx, html| bibliography_pass(ctx, html),
+        },
+        PassMacro {
+            name: "toc",
+            phase: Phase::Document,
+            after: vec!["numbering"],
+            pass: |ctx, html| toc_tag(ctx.env, html),
+        },
+        PassMacro {
+            name: "site-toc",
+            phase: Phase::Finalize,
+            after: Vec::new(),
+            pass: |ctx, html| site_toc_tag(ctx.env, ctx.site_pages.clone(), html),
+        },
+        PassMacro {
+            name: "ref",
+            phase: Phase::Finalize,
+            after: Vec::new(),
+            pass: |ctx, html| ref_tag(ctx, html),
+        },
+        PassMacro {
+            name: "glossary",
+            phase: Phase::Finalize,
+            after: Vec::new(),
+            pass: |ctx, html| glossary_tags(ctx.env, &ctx.site_pages, html),
+        },
+    ]
+}
+
+pub fn postproc_document_macros(env: &Env, html: &mut Node) {
+    registry::apply(env, Arc::default(), Phase::Document, html);
+}
+
+/// Runs once every page has been through `postproc_document_macros`.
+pub fn postproc_site_macros(env: &Env, site_pages: Arc<[SitePage]>, html: &mut Node) {
+    registry::apply(env, site_pages, Phase::Finalize, html);
+}
+
+#[cfg(test)]
+mod t_attr_macros {
+    use super::*;
+
+    fn markdown(source: &str) -> String {
+        Node::Fragment(markdown_children(Node::parse_str(source).into_fragment())).to_html_str(0)
+    }
+
+    fn smarten(source: &str) -> String {
+        let mut html = Node::parse_str(source);
+        typography_node(&mut html, &mut None);
+        html.to_html_str(0)
+    }
+
+    #[test]
+    fn markdown_leaves_math_alone() {
+        let html = markdown("Let *a* be <tex>x_1 * y_2 * z_3</tex>, and _b_ too.");
+        assert!(html.contains("<em>a</em>"), "{}", html);
+        assert!(html.contains("<em>b</em>"), "{}", html);
+        assert!(html.contains("<tex>x_1 * y_2 * z_3</tex>"), "{}", html);
+    }
+
+    #[test]
+    fn markdown_leaves_code_and_block_math_alone() {
+        let source = "Some `code`\n\n<equation>a_1 = *b*</equation>\n\nThen <code>__init__</code>";
+        let nodes = markdown_children(Node::parse_str(source).into_fragment());
+        let tags = nodes.iter().filter_map(|x| x.tag()).collect::<Vec<_>>();
+        assert_eq!(tags, vec!["p", "equation", "p"]);
+        assert_eq!(nodes[1].get_children_as_text().concat(), "a_1 = *b*");
+        let html = Node::Fragment(nodes).to_html_str(0);
+        assert!(html.contains("<code>code</code>"), "{}", html);
+        assert!(html.contains("<code>__init__</code>"), "{}", html);
+        assert!(!html.contains("<em>"), "{}", html);
+    }
+
+    #[test]
+    fn quotes_around_inline_elements() {
+        let html = smarten("<p>\"<em>x</em>\" and 'it's <b>y</b>'</p>");
+        assert!(html.contains("\u{201C}<em>x</em>\u{201D}"), "{}", html);
+        assert!(html.contains("\u{2018}it\u{2019}s <b>y</b>\u{2019}"), "{}", html);
+    }
+
+    #[test]
+    fn quotes_after_math_and_in_new_blocks() {
+        let html = smarten("<div><p>a <tex>x</tex>\"</p><p>\"b\"</p></div>");
+        assert!(html.contains("<tex>x</tex>\u{201D}"), "{}", html);
+        assert!(html.contains("\u{201C}b\u{201D}"), "{}", html);
+    }
+}
+
+#[cfg(test)]
+mod t_shortcodes {
+    use super::*;
+
+    fn text(x: &str) -> Shortcode {
+        Shortcode::Text(x.to_owned())
+    }
+
+    fn call(tag: &str, arg: &str, source: &str) -> Shortcode {
+        Shortcode::Call {tag: tag.to_owned(), arg: arg.to_owned(), source: source.to_owned()}
+    }
+
+    #[test]
+    fn calls_and_page_keys() {
+        assert_eq!(parse_shortcodes("see {{ref:eq-1}} and @cite{knuth84}."), vec![
+            text("see "),
+            call("ref", "eq-1", "{{ref:eq-1}}"),
+            text(" and "),
+            call("cite", "knuth84", "@cite{knuth84}"),
+            text("."),
+        ]);
+        assert_eq!(parse_shortcodes("{{ page.title }}"), vec![
+            Shortcode::Page {key: "title".to_owned(), source: "{{ page.title }}".to_owned()},
+        ]);
+        assert_eq!(parse_shortcodes("{{ tag : arg }}"), vec![call("tag", "arg", "{{ tag : arg }}")]);
+    }
+
+    #[test]
+    fn nested_braces() {
+        assert_eq!(parse_shortcodes("{{ref:{a}}}!"), vec![call("ref", "{a}", "{{ref:{a}}}"), text("!")]);
+        let bibtex = "@article{knuth84, title={The {TeX}book}}";
+        assert_eq!(parse_shortcodes(bibtex), vec![
+            call("article", "knuth84, title={The {TeX}book}", bibtex),
+        ]);
+    }
+
+    #[test]
+    fn unterminated() {
+        for source in &["{{ref:a", "{{ref:a}", "{{ref:{a}}", "@cite{a", "@cite{{a}", "{{page.title"] {
+            assert_eq!(parse_shortcodes(source), vec![text(source)]);
+        }
+        assert_eq!(parse_shortcodes("{{ref:a {{ref:b}}"), vec![
+            text("{{ref:a "),
+            call("ref", "b", "{{ref:b}}"),
+        ]);
+    }
+
+    #[test]
+    fn not_shortcodes() {
+        for source in &["@", "a @", "@{x}", "me@example.com{x}", "{{}}", "{{no colon}}", "{{a b:c}}", "{{page.}}"] {
+            assert_eq!(parse_shortcodes(source), vec![text(source)]);
+        }
+    }
+
+    #[test]
+    fn skips_code_and_math() {
+        let mut html = Node::parse_str(
+            "<p>{{ref:a}} <code>{{ref:b}}</code> <tex>@c{d}</tex> <span latex=\"inline\">@e{f}</span> @g{h}</p>"
+        );
+        let mut calls = Vec::new();
+        for_each_prose_text(&mut html, &mut |node| {
+            if let Node::Text(text) = node {
+                for shortcode in parse_shortcodes(text) {
+                    if let Shortcode::Call {tag, ..} = shortcode {
+                        calls.push(tag);
+                    }
+                }
+            }
+        });
+        assert_eq!(calls, vec!["ref", "g"]);
+    }
+}
+
+#[cfg(test)]
+mod t_include {
+    use super::*;
+
+    /// Builds the first of `files` (written to a temporary directory), returning
+    /// the output and the warnings, with paths relative to that directory.
+    fn build(name: &str, files: &[(&str, &str)], max_include_depth: usize) -> (String, Vec<String>) {
+        let dir = std::env::temp_dir().join(format!("ss-include-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            std::fs::write(dir.join(file), contents).unwrap();
+        }
+        let mut env = Env::for_test(&dir.join(files[0].0));
+        env.max_include_depth = max_include_depth;
+        let mut html = Node::parse_str(files[0].1);
+        crate::frontend::apply_macros(&env, &mut html);
+        let prefix = format!("{}/", dir.canonicalize().unwrap().display());
+        let warnings = env.diagnostics.access(|xs| {
+            xs.iter().map(|x| x.message.replace(&prefix, "")).collect()
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        (html.to_html_str(0), warnings)
+    }
+
+    /// `page.html` → `f1.html` → ... → `f{depth}.html`.
+    fn chain(depth: usize) -> Vec<(String, String)> {
+        let mut files = vec![(String::from("page.html"), String::from(r#"<include src="./f1.html"></include>"#))];
+        for ix in 1..=depth {
+            let next = if ix < depth {
+                format!(r#"<include src="./f{}.html"></include>"#, ix + 1)
+            } else {
+                String::new()
+            };
+            files.push((format!("f{}.html", ix), format!("<p>f{}</p>{}", ix, next)));
+        }
+        files
+    }
+
+    fn build_chain(name: &str, depth: usize, max_include_depth: usize) -> (String, Vec<String>) {
+        let files = chain(depth);
+        let files = files.iter().map(|(a, b)| (a.as_str(), b.as_str())).collect::<Vec<_>>();
+        build(name, &files, max_include_depth)
+    }
+
+    #[test]
+    fn self_include() {
+        let (html, warnings) = build("self", &[
+            ("page.html", r#"<p>page</p><include src="./a.html"></include>"#),
+            ("a.html", r#"<p>a</p><include src="./a.html"></include>"#),
+        ], 32);
+        assert!(html.contains("<p>a</p>"), "{}", html);
+        assert_eq!(warnings, vec!["include cycle: a.html → a.html"]);
+    }
+
+    #[test]
+    fn indirect_cycle() {
+        let (html, warnings) = build("indirect", &[
+            ("page.html", r#"<include src="./a.html"></include>"#),
+            ("a.html", r#"<p>a</p><include src="./b.html"></include>"#),
+            ("b.html", r#"<p>b</p><include src="./a.html"></include>"#),
+        ], 32);
+        assert!(html.contains("<p>a</p>") && html.contains("<p>b</p>"), "{}", html);
+        assert_eq!(warnings, vec!["include cycle: a.html → b.html → a.html"]);
+    }
+
+    #[test]
+    fn depth_limit() {
+        let (html, warnings) = build_chain("depth-ok", 3, 3);
+        assert!(html.contains("<p>f3</p>"), "{}", html);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let (html, warnings) = build_chain("depth-exceeded", 4, 3);
+        assert!(html.contains("<p>f3</p>") && !html.contains("<p>f4</p>"), "{}", html);
+        assert_eq!(warnings, vec![
+            "include depth limit (3) exceeded: page.html → f1.html → f2.html → f3.html → f4.html",
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod t_numbering {
+    use super::*;
+
+    /// Numbers each page, then resolves the `<ref>`s on the first one.
+    fn build(pages: &[(&str, &str)]) -> (String, Vec<String>) {
+        let pages = pages
+            .iter()
+            .map(|(path, source)| {
+                let env = Env::for_test(&PathBuf::from(path));
+                let mut html = Node::parse_str(source);
+                numbering_pass(&env, &mut html);
+                (env, html)
+            })
+            .collect::<Vec<_>>();
+        let site_pages = pages
+            .iter()
+            .map(|(env, html)| SitePage::new(env, html))
+            .collect::<Arc<[_]>>();
+        let (env, html) = &pages[0];
+        let mut html = html.clone();
+        ref_tag(&MacroContext::new(env, site_pages), &mut html);
+        let warnings = env.diagnostics.access(|xs| xs.iter().map(|x| x.message.clone()).collect());
+        (html.to_html_str(0), warnings)
+    }
+
+    fn select(html: &str, selector: &str, attr: &str) -> Vec<String> {
+        Node::parse_str(html)
+            .select(selector)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.get_attr(attr).unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn skipped_section_levels() {
+        let (html, _) = build(&[(
+            "page.html",
+            r#"<h2 label="a">A</h2><h4 label="b">B</h4><h2 label="c">C</h2><h3>D</h3><h4 label="e">E</h4>"#,
+        )]);
+        assert_eq!(select(&html, "[ref-number]", "ref-number"), vec!["1", "1.1", "2", "2.1.1"]);
+    }
+
+    #[test]
+    fn refs() {
+        let (html, warnings) = build(&[
+            ("page.html", r#"<h2 label="a">A</h2><h4 label="b">B</h4>
+<p><ref to="a"></ref> <ref to="b"></ref> <ref to="f">see</ref> <ref to="nope"></ref></p>"#),
+            ("other.html", r#"<h2>X</h2><h3 label="f">F</h3>"#),
+        ]);
+        assert_eq!(select(&html, "a[macro=ref]", "href"), vec!["#a", "#b", "/other.html#f"]);
+        let text = Node::parse_str(&html)
+            .select("[macro=ref]")
+            .unwrap()
+            .into_iter()
+            .map(|x| x.get_children_as_text().concat())
+            .collect::<Vec<_>>();
+        assert_eq!(text, vec!["§1", "§1.1", "see §1.1", "??"]);
+        assert_eq!(warnings, vec!["unknown label \"nope\""]);
+    }
+}
@@ -0,0 +1,820 @@
+//! The public macro API. Downstream crates implement [`Macro`] and add it to
+//! the [`MacroRegistry`] via `frontend::main_with`, e.g.
+//!
+//! ```ignore
+//! struct Shout;
+//! impl Macro for Shout {
+//!     fn name(&self) -> &str {"shout"}
+//!     fn tags(&self) -> Vec<String> {vec![String::from("shout")]}
+//!     fn expand(&self, _: &mut MacroContext, node: &mut Node) -> Result<(), MacroError> {
+//!         let text = node.get_text_contents().unwrap_or_default().to_uppercase();
+//!         *node = Node::new_text(&text);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! fn main() {
+//!     subscript_html::frontend::main_with(|registry| registry.register(Shout));
+//! }
+//! ```
+use std::sync::{Arc, OnceLock};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::cmp::Reverse;
+use std::path::PathBuf;
+
+use crate::data::{Node, TagMacro, MacroCallbackMut, Store};
+use crate::frontend::{Env, IoPath};
+use crate::macros::{Component, SitePage};
+
+/// When a macro runs, in this order. Within a phase, macros run in
+/// registration order, unless constrained by `Macro::after`/`Macro::before`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// Bottom-up, as each file is parsed, before the `Expand` macros of the
+    /// same element: `<include>` and user-defined components.
+    Include,
+    /// Bottom-up, as each file is parsed. Relative paths are resolved against
+    /// the file being expanded, so `<include>`'d files are expanded before
+    /// they're spliced into the page.
+    Expand,
+    /// Once the whole page has been expanded, e.g. numbering and footnotes.
+    Document,
+    /// Once every page has been through `Document`, with `MacroContext::site_pages`
+    /// available, e.g. cross-references.
+    Finalize,
+}
+
+/// An element enclosing the node being expanded, as it was before its own
+/// macros ran (macros run bottom-up). Changes are written back to the
+/// element.
+#[derive(Debug, Clone)]
+pub struct Ancestor {
+    pub tag: String,
+    pub attrs: HashMap<String, String>,
+}
+
+pub struct MacroContext<'a> {
+    pub env: &'a Env,
+    /// Empty before `Phase::Finalize`; shared by every page, not copied.
+    pub site_pages: Arc<[SitePage]>,
+    /// Innermost last, within the file being expanded.
+    ancestors: Vec<Ancestor>,
+    sibling_index: usize,
+    sibling_count: usize,
+    /// The macro being run, for diagnostics.
+    macro_name: String,
+}
+
+impl<'a> MacroContext<'a> {
+    pub fn new(env: &'a Env, site_pages: Arc<[SitePage]>) -> Self {
+        MacroContext {
+            env,
+            site_pages,
+            ancestors: Vec::new(),
+            sibling_index: 0,
+            sibling_count: 1,
+            macro_name: String::new(),
+        }
+    }
+    pub fn ancestors(&self) -> &[Ancestor] {
+        &self.ancestors
+    }
+    pub fn parent(&self) -> Option<&Ancestor> {
+        self.ancestors.last()
+    }
+    pub fn parent_mut(&mut self) -> Option<&mut Ancestor> {
+        self.ancestors.last_mut()
+    }
+    /// The innermost ancestor matching the given CSS selector (which may
+    /// use combinators, matched against the ancestors further out).
+    pub fn closest(&self, selector: &str) -> Option<&Ancestor> {
+        let selector = match crate::data::selector::Selector::parse(selector) {
+            Ok(x) => x,
+            Err(msg) => {
+                self.warn(msg);
+                return None;
+            }
+        };
+        let refs = self.ancestors
+            .iter()
+            .map(|x| crate::data::selector::ElementRef {tag: &x.tag, attrs: &x.attrs})
+            .collect::<Vec<_>>();
+        (0..refs.len())
+            .rev()
+            .find(|ix| selector.matches(refs[*ix], &refs[..*ix]))
+            .map(|ix| &self.ancestors[ix])
+    }
+    /// The position of the node among its parent's children, with fragments
+    /// flattened.
+    pub fn sibling_index(&self) -> usize {
+        self.sibling_index
+    }
+    pub fn sibling_count(&self) -> usize {
+        self.sibling_count
+    }
+    /// The page being built.
+    pub fn io_path(&self) -> &IoPath {
+        &self.env.io_path
+    }
+    /// Key/value pairs for the current page, kept across phases.
+    pub fn metadata(&self) -> &Store<HashMap<String, String>> {
+        &self.env.metadata
+    }
+    /// Key/value pairs shared by every page of the build. Pages are built in
+    /// parallel, so nothing should depend on the order of writes.
+    pub fn shared(&self) -> &Store<HashMap<String, serde_json::Value>> {
+        &self.env.shared
+    }
+    pub fn warn(&self, message: impl Into<String>) {
+        self.report(Level::Warning, message.into());
+    }
+    pub fn error(&self, message: impl Into<String>) {
+        self.report(Level::Error, message.into());
+    }
+    fn report(&self, level: Level, message: String) {
+        let diagnostic = Diagnostic {
+            level,
+            page: self.env.io_path.input_file.clone(),
+            macro_name: Some(self.macro_name.clone()).filter(|x| !x.is_empty()),
+            message,
+        };
+        self.env.diagnostics.access_mut(|xs| xs.push(diagnostic.clone()));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Warning,
+    Error,
+}
+
+/// Reported by macros via `MacroContext::warn`/`MacroContext::error`, and
+/// printed once the build is done.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub page: PathBuf,
+    pub macro_name: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// A warning that isn't reported by a macro, e.g. about a component file.
+    pub fn warning(page: impl Into<PathBuf>, message: impl Into<String>) -> Self {
+        Diagnostic {
+            level: Level::Warning,
+            page: page.into(),
+            macro_name: None,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = match self.level {
+            Level::Warning => "warning",
+            Level::Error => "error",
+        };
+        write!(f, "[{}] {}: ", level, self.page.to_str().unwrap_or_default())?;
+        if let Some(macro_name) = self.macro_name.as_ref() {
+            write!(f, "<{}>: ", macro_name)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MacroError {
+    pub message: String,
+}
+
+impl MacroError {
+    pub fn new(message: impl Into<String>) -> Self {
+        MacroError {message: message.into()}
+    }
+}
+
+/// E.g. from `Node::select`, so `expand` can use `?`.
+impl From<String> for MacroError {
+    fn from(message: String) -> Self {
+        MacroError::new(message)
+    }
+}
+
+impl std::fmt::Display for MacroError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Macros are shared by every page (and thread) of a build. State that can't
+/// be shared, e.g. a script engine or WASM instance, can be kept per thread in
+/// a `thread_local!`, or behind a `Mutex`.
+pub trait Macro: Send + Sync {
+    /// Used in diagnostics and ordering constraints.
+    fn name(&self) -> &str;
+    /// Elements with any of these tags are passed to `expand`.
+    fn tags(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Elements with any of these attributes are passed to `expand`. Macros
+    /// that match neither tags nor attributes are passed the whole page (or
+    /// file, in `Phase::Include` and `Phase::Expand`).
+    fn attributes(&self) -> Vec<String> {
+        Vec::new()
+    }
+    fn phase(&self) -> Phase {
+        Phase::Expand
+    }
+    /// Names of macros in the same phase that must run first.
+    fn after(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Names of macros in the same phase that must run afterwards.
+    fn before(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Run on matching elements before their children are expanded (e.g. to
+    /// see their source), rather than after.
+    fn pre_order(&self) -> bool {
+        false
+    }
+    /// On failure the error is reported and the node is left as is (or as far
+    /// as the macro got).
+    fn expand(&self, ctx: &mut MacroContext, node: &mut Node) -> Result<(), MacroError>;
+}
+
+/// Triggered by an attribute on any element, e.g. `<div ss:markdown>`.
+#[derive(Clone)]
+pub struct AttrMacro {
+    pub attr: String,
+    pub callback: MacroCallbackMut,
+}
+
+/// A whole-page pass, e.g. numbering, run once per page in the given phase.
+#[derive(Clone)]
+pub struct PassMacro {
+    pub name: &'static str,
+    pub phase: Phase,
+    /// Passes (in the same phase) that must run first.
+    pub after: Vec<&'static str>,
+    pub pass: fn(&mut MacroContext, &mut Node),
+}
+
+impl Macro for TagMacro {
+    fn name(&self) -> &str {
+        &self.tag
+    }
+    fn tags(&self) -> Vec<String> {
+        vec![self.tag.clone()]
+    }
+    fn expand(&self, ctx: &mut MacroContext, node: &mut Node) -> Result<(), MacroError> {
+        (self.callback.0)(ctx, node);
+        Ok(())
+    }
+}
+
+impl Macro for AttrMacro {
+    fn name(&self) -> &str {
+        &self.attr
+    }
+    fn attributes(&self) -> Vec<String> {
+        vec![self.attr.clone()]
+    }
+    fn expand(&self, ctx: &mut MacroContext, node: &mut Node) -> Result<(), MacroError> {
+        (self.callback.0)(ctx, node);
+        Ok(())
+    }
+}
+
+impl Macro for PassMacro {
+    fn name(&self) -> &str {
+        self.name
+    }
+    fn phase(&self) -> Phase {
+        self.phase
+    }
+    fn after(&self) -> Vec<String> {
+        self.after.iter().map(|x| x.to_string()).collect()
+    }
+    fn expand(&self, ctx: &mut MacroContext, node: &mut Node) -> Result<(), MacroError> {
+        (self.pass)(ctx, node);
+        Ok(())
+    }
+}
+
+/// Runs a macro in a different phase.
+pub struct InPhase<M>(pub Phase, pub M);
+
+impl<M: Macro> Macro for InPhase<M> {
+    fn name(&self) -> &str {self.1.name()}
+    fn tags(&self) -> Vec<String> {self.1.tags()}
+    fn attributes(&self) -> Vec<String> {self.1.attributes()}
+    fn phase(&self) -> Phase {self.0}
+    fn after(&self) -> Vec<String> {self.1.after()}
+    fn before(&self) -> Vec<String> {self.1.before()}
+    fn pre_order(&self) -> bool {self.1.pre_order()}
+    fn expand(&self, ctx: &mut MacroContext, node: &mut Node) -> Result<(), MacroError> {
+        self.1.expand(ctx, node)
+    }
+}
+
+/// Runs a macro before the children of the elements it matches are expanded,
+/// see `Macro::pre_order`.
+pub struct PreOrder<M>(pub M);
+
+impl<M: Macro> Macro for PreOrder<M> {
+    fn name(&self) -> &str {self.0.name()}
+    fn tags(&self) -> Vec<String> {self.0.tags()}
+    fn attributes(&self) -> Vec<String> {self.0.attributes()}
+    fn phase(&self) -> Phase {self.0.phase()}
+    fn after(&self) -> Vec<String> {self.0.after()}
+    fn before(&self) -> Vec<String> {self.0.before()}
+    fn pre_order(&self) -> bool {true}
+    fn expand(&self, ctx: &mut MacroContext, node: &mut Node) -> Result<(), MacroError> {
+        self.0.expand(ctx, node)
+    }
+}
+
+/// Built once per build; macros are indexed by the tags and attributes they
+/// match, and sorted by phase and ordering constraints.
+#[derive(Clone, Default)]
+pub struct MacroRegistry {
+    macros: Vec<Registered>,
+    by_tag: HashMap<String, Vec<usize>>,
+    by_attribute: HashMap<String, Vec<usize>>,
+    /// Computed on first use, once every macro has been registered.
+    schedule: OnceLock<Schedule>,
+}
+
+/// A macro, with what's needed to match it against elements cached when it's
+/// registered, rather than calling `Macro::tags` etc. for every node.
+#[derive(Clone)]
+struct Registered {
+    inner: Arc<dyn Macro>,
+    phase: Phase,
+    pre_order: bool,
+    tags: HashSet<String>,
+    attributes: Vec<String>,
+}
+
+impl Registered {
+    fn new(inner: Arc<dyn Macro>) -> Self {
+        Registered {
+            phase: inner.phase(),
+            pre_order: inner.pre_order(),
+            tags: inner.tags().into_iter().collect(),
+            attributes: inner.attributes(),
+            inner,
+        }
+    }
+    fn is_whole_page(&self) -> bool {
+        self.tags.is_empty() && self.attributes.is_empty()
+    }
+    fn matches(&self, node: &Node) -> bool {
+        match node {
+            Node::Element(element) => {
+                self.tags.contains(&element.tag) ||
+                self.attributes.iter().any(|attr| element.attrs.contains_key(attr))
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Schedule {
+    /// Indices into `MacroRegistry::macros`, in the order they run.
+    order: Vec<usize>,
+    /// The position of each macro in `order`.
+    rank: Vec<usize>,
+}
+
+impl MacroRegistry {
+    pub fn new() -> Self {
+        MacroRegistry::default()
+    }
+    /// The macros defined in `crate::macros`, plus the given user-defined
+    /// components.
+    pub fn builtin(components: &[Component]) -> Self {
+        let mut registry = MacroRegistry::new();
+        let mut builtins = vec![crate::macros::include_tag()];
+        builtins.extend(crate::macros::tag_macros());
+        let component_tags = crate::macros::component_tags(components, &builtins);
+        let mut builtins = builtins.into_iter();
+        registry.register(InPhase(Phase::Include, builtins.next().unwrap()));
+        for component in component_tags {
+            registry.register(InPhase(Phase::Include, component));
+        }
+        for tag_macro in builtins {
+            registry.register(tag_macro);
+        }
+        registry.register(PreOrder(crate::macros::markdown_attr()));
+        for attr_macro in crate::macros::attr_macros() {
+            registry.register(attr_macro);
+        }
+        for pass_macro in crate::macros::pass_macros() {
+            registry.register(pass_macro);
+        }
+        registry
+    }
+    pub fn register<M: Macro + 'static>(&mut self, x: M) {
+        let ix = self.macros.len();
+        let x = Registered::new(Arc::new(x));
+        for tag in x.tags.iter() {
+            self.by_tag.entry(tag.clone()).or_default().push(ix);
+        }
+        for attribute in x.attributes.iter() {
+            self.by_attribute.entry(attribute.clone()).or_default().push(ix);
+        }
+        self.macros.push(x);
+        self.schedule = OnceLock::new();
+    }
+    /// In the order they run.
+    pub fn iter(&self) -> impl Iterator<Item=&Arc<dyn Macro>> {
+        self.registered().map(|x| &x.inner)
+    }
+    fn registered(&self) -> impl Iterator<Item=&Registered> {
+        self.schedule().order.iter().map(move |ix| &self.macros[*ix])
+    }
+    fn schedule(&self) -> &Schedule {
+        self.schedule.get_or_init(|| self.sort())
+    }
+    /// Orders the macros by phase, then topologically by `after`/`before`
+    /// (ties broken by registration order). Constraints on a cycle are reported
+    /// and ignored.
+    fn sort(&self) -> Schedule {
+        let names = self.macros
+            .iter()
+            .enumerate()
+            .map(|(ix, x)| ((x.phase, x.inner.name().to_owned()), ix))
+            .fold(HashMap::<_, Vec<usize>>::new(), |mut names, (key, ix)| {
+                names.entry(key).or_default().push(ix);
+                names
+            });
+        // Edges from each macro to those that must run after it.
+        let mut edges = vec![Vec::<usize>::new(); self.macros.len()];
+        let mut in_degree = vec![0usize; self.macros.len()];
+        for (ix, x) in self.macros.iter().enumerate() {
+            let lookup = |name: &String| {
+                names.get(&(x.phase, name.clone())).cloned().unwrap_or_default()
+            };
+            for before in x.inner.after().iter().flat_map(lookup) {
+                edges[before].push(ix);
+                in_degree[ix] += 1;
+            }
+            for after in x.inner.before().iter().flat_map(lookup) {
+                edges[ix].push(after);
+                in_degree[after] += 1;
+            }
+        }
+        // Constraints only apply within a phase, so each phase is sorted on
+        // its own, taking the earliest registered macro that's ready.
+        let mut phases = self.macros
+            .iter()
+            .enumerate()
+            .map(|(ix, x)| (x.phase, ix))
+            .collect::<Vec<_>>();
+        phases.sort();
+        let mut order = Vec::with_capacity(self.macros.len());
+        let mut done = vec![false; self.macros.len()];
+        for group in phases.chunk_by(|a, b| a.0 == b.0) {
+            let mut ready = group
+                .iter()
+                .filter(|(_, ix)| in_degree[*ix] == 0)
+                .map(|(_, ix)| Reverse(*ix))
+                .collect::<BinaryHeap<_>>();
+            let mut remaining = group.len();
+            while remaining > 0 {
+                let next = match ready.pop() {
+                    Some(Reverse(ix)) if done[ix] => continue,
+                    Some(Reverse(ix)) => ix,
+                    None => {
+                        let ix = group
+                            .iter()
+                            .map(|(_, ix)| *ix)
+                            .find(|ix| !done[*ix])
+                            .unwrap();
+                        eprintln!(
+                            "[warning] ignoring cyclic ordering constraints of macro {:?}",
+                            self.macros[ix].inner.name(),
+                        );
+                        ix
+                    }
+                };
+                done[next] = true;
+                remaining -= 1;
+                order.push(next);
+                for after in edges[next].iter() {
+                    in_degree[*after] = in_degree[*after].saturating_sub(1);
+                    if in_degree[*after] == 0 && !done[*after] {
+                        ready.push(Reverse(*after));
+                    }
+                }
+            }
+        }
+        let mut rank = vec![0; order.len()];
+        for (ix, macro_ix) in order.iter().enumerate() {
+            rank[*macro_ix] = ix;
+        }
+        Schedule {order, rank}
+    }
+    /// Whether any macro matches elements with the given tag.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.by_tag.contains_key(tag)
+    }
+    /// Macros of the given phases that match the tag or an attribute of `node`,
+    /// in the order they run.
+    pub fn matching(&self, phases: &[Phase], node: &Node) -> Vec<Arc<dyn Macro>> {
+        self.matching_registered(phases, node)
+            .into_iter()
+            .map(|x| x.inner.clone())
+            .collect()
+    }
+    fn matching_registered(&self, phases: &[Phase], node: &Node) -> Vec<&Registered> {
+        let element = match node {
+            Node::Element(element) => element,
+            _ => return Vec::new(),
+        };
+        let mut ixs = self.by_tag
+            .get(&element.tag)
+            .cloned()
+            .unwrap_or_default();
+        for attribute in element.attrs.keys() {
+            if let Some(xs) = self.by_attribute.get(attribute) {
+                ixs.extend(xs);
+            }
+        }
+        let rank = &self.schedule().rank;
+        ixs.sort_by_key(|ix| rank[*ix]);
+        ixs.dedup();
+        ixs
+            .into_iter()
+            .map(|ix| &self.macros[ix])
+            .filter(|x| phases.contains(&x.phase))
+            .collect()
+    }
+}
+
+fn run(x: &Arc<dyn Macro>, ctx: &mut MacroContext, node: &mut Node) {
+    ctx.macro_name = x.name().to_owned();
+    if let Err(error) = x.expand(ctx, node) {
+        ctx.error(error.message);
+    }
+    ctx.macro_name.clear();
+}
+
+/// Runs the macros of the given phase over `html`.
+///
+/// `Phase::Include` and `Phase::Expand` are a single bottom-up pass, with the
+/// `Include` macros of each element running first (see
+/// `crate::frontend::apply_macros`). In later phases whole-page macros run in
+/// turn, and consecutive macros matching elements share a bottom-up pass, so
+/// a macro sees the output of the macros before it on the same element and
+/// its descendants.
+pub fn apply(env: &Env, site_pages: Arc<[SitePage]>, phase: Phase, html: &mut Node) {
+    let registry = env.macro_system.registry.clone();
+    let mut ctx = MacroContext::new(env, site_pages);
+    match phase {
+        Phase::Include | Phase::Expand => {
+            let phases = [Phase::Include, Phase::Expand];
+            bottom_up(html, &mut ctx, &mut |ctx, node, pre_order| {
+                for x in registry.matching_registered(&phases, node) {
+                    if x.pre_order == pre_order {
+                        run(&x.inner, ctx, node);
+                    }
+                }
+            });
+            for x in registry.registered().filter(|x| phases.contains(&x.phase) && x.is_whole_page()) {
+                run(&x.inner, &mut ctx, html);
+            }
+        }
+        Phase::Document | Phase::Finalize => {
+            let mut batch = Vec::new();
+            for x in registry.registered().filter(|x| x.phase == phase) {
+                if x.is_whole_page() {
+                    run_batch(&batch, &mut ctx, html);
+                    batch.clear();
+                    run(&x.inner, &mut ctx, html);
+                } else {
+                    batch.push(x);
+                }
+            }
+            run_batch(&batch, &mut ctx, html);
+        }
+    }
+}
+
+/// Runs macros that match elements in a single bottom-up pass.
+fn run_batch(batch: &[&Registered], ctx: &mut MacroContext, html: &mut Node) {
+    if batch.is_empty() {
+        return;
+    }
+    bottom_up(html, ctx, &mut |ctx, node, pre_order| {
+        for x in batch.iter() {
+            if x.pre_order == pre_order && x.matches(node) {
+                run(&x.inner, ctx, node);
+            }
+        }
+    });
+}
+
+/// Like `Node::eval`, keeping track of ancestors and sibling indices. `f` is
+/// also called before a node's children are visited, with `true`, for
+/// `Macro::pre_order` macros.
+fn bottom_up(
+    node: &mut Node,
+    ctx: &mut MacroContext,
+    f: &mut dyn FnMut(&mut MacroContext, &mut Node, bool),
+) {
+    let (index, count) = (ctx.sibling_index, ctx.sibling_count);
+    ctx.sibling_index = 0;
+    ctx.sibling_count = 1;
+    walk(node, ctx, f);
+    ctx.sibling_index = index;
+    ctx.sibling_count = count;
+}
+
+/// Number of nodes in `xs`, with fragments flattened.
+fn flat_len(xs: &[Node]) -> usize {
+    xs.iter()
+        .map(|x| match x {
+            Node::Fragment(xs) => flat_len(xs),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Leaves `ctx.sibling_index` just past `node`.
+fn walk(
+    node: &mut Node,
+    ctx: &mut MacroContext,
+    f: &mut dyn FnMut(&mut MacroContext, &mut Node, bool),
+) {
+    let index = ctx.sibling_index;
+    f(ctx, node, true);
+    match node {
+        Node::Element(element) => {
+            // Moved rather than copied, the element is restored (with any
+            // changes) once its children are done.
+            ctx.ancestors.push(Ancestor {
+                tag: std::mem::take(&mut element.tag),
+                attrs: std::mem::take(&mut element.attrs),
+            });
+            let count = ctx.sibling_count;
+            ctx.sibling_index = 0;
+            ctx.sibling_count = flat_len(&element.children);
+            for child in element.children.iter_mut() {
+                walk(child, ctx, f);
+            }
+            ctx.sibling_count = count;
+            let Ancestor {tag, attrs} = ctx.ancestors.pop().unwrap();
+            element.tag = tag;
+            element.attrs = attrs;
+        }
+        Node::Fragment(xs) => {
+            for x in xs.iter_mut() {
+                walk(x, ctx, f);
+            }
+        }
+        Node::Text(_) => {}
+    }
+    let end = match node {
+        Node::Fragment(_) => ctx.sibling_index,
+        _ => index + 1,
+    };
+    ctx.sibling_index = index;
+    f(ctx, node, false);
+    ctx.sibling_index = end;
+}
+
+#[cfg(test)]
+mod t_registry {
+    use super::*;
+
+    struct Named {
+        name: &'static str,
+        phase: Phase,
+        after: Vec<&'static str>,
+        before: Vec<&'static str>,
+    }
+
+    impl Macro for Named {
+        fn name(&self) -> &str {self.name}
+        fn phase(&self) -> Phase {self.phase}
+        fn after(&self) -> Vec<String> {self.after.iter().map(|x| x.to_string()).collect()}
+        fn before(&self) -> Vec<String> {self.before.iter().map(|x| x.to_string()).collect()}
+        fn expand(&self, _: &mut MacroContext, _: &mut Node) -> Result<(), MacroError> {
+            Ok(())
+        }
+    }
+
+    fn named(name: &'static str, after: &[&'static str], before: &[&'static str]) -> Named {
+        Named {name, phase: Phase::Document, after: after.to_vec(), before: before.to_vec()}
+    }
+
+    fn order(registry: &MacroRegistry) -> Vec<&str> {
+        registry.iter().map(|x| x.name()).collect()
+    }
+
+    #[test]
+    fn registration_order() {
+        let mut registry = MacroRegistry::new();
+        registry.register(named("a", &[], &[]));
+        registry.register(named("b", &[], &[]));
+        registry.register(named("c", &[], &[]));
+        assert_eq!(order(&registry), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn after_and_before() {
+        let mut registry = MacroRegistry::new();
+        registry.register(named("a", &["c"], &[]));
+        registry.register(named("b", &[], &[]));
+        registry.register(named("c", &[], &[]));
+        registry.register(named("d", &[], &["b"]));
+        assert_eq!(order(&registry), vec!["c", "a", "d", "b"]);
+    }
+
+    #[test]
+    fn phases_come_first() {
+        let mut registry = MacroRegistry::new();
+        registry.register(Named {phase: Phase::Finalize, ..named("late", &[], &[])});
+        registry.register(named("a", &[], &[]));
+        registry.register(Named {phase: Phase::Expand, ..named("early", &[], &[])});
+        // Constraints across phases are ignored.
+        registry.register(Named {phase: Phase::Include, ..named("first", &["a"], &[])});
+        assert_eq!(order(&registry), vec!["first", "early", "a", "late"]);
+    }
+
+    #[test]
+    fn registering_resets_the_order() {
+        let mut registry = MacroRegistry::new();
+        registry.register(named("a", &["b"], &[]));
+        assert_eq!(order(&registry), vec!["a"]);
+        registry.register(named("b", &[], &[]));
+        assert_eq!(order(&registry), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn cycles_fall_back_to_registration_order() {
+        let mut registry = MacroRegistry::new();
+        registry.register(named("x", &[], &[]));
+        registry.register(named("a", &["b"], &[]));
+        registry.register(named("b", &["a"], &[]));
+        registry.register(named("c", &["a"], &[]));
+        assert_eq!(order(&registry), vec!["x", "a", "b", "c"]);
+    }
+
+    fn p(id: &str) -> Node {
+        let attrs = vec![(String::from("id"), id.to_owned())].into_iter().collect();
+        Node::new_element("p", attrs, Vec::new())
+    }
+
+    #[test]
+    fn parent_attributes_survive_the_walk() {
+        let env = Env::for_test(&PathBuf::from("page.html"));
+        let mut ctx = MacroContext::new(&env, Arc::default());
+        let mut html = Node::parse_str(r#"<div class="a"><section><p></p><p></p></section></div>"#);
+        bottom_up(&mut html, &mut ctx, &mut |ctx, node, pre_order| {
+            if pre_order || !node.is_tag("p") {
+                return;
+            }
+            let tags = ctx.ancestors().iter().map(|x| x.tag.as_str()).collect::<Vec<_>>();
+            assert_eq!(tags, vec!["div", "section"]);
+            let parent = ctx.parent_mut().unwrap();
+            let seen = parent.attrs.get("seen").map_or(0, |x| x.parse().unwrap());
+            parent.attrs.insert(String::from("seen"), (seen + 1).to_string());
+        });
+        assert_eq!(
+            html.to_html_str(0).split_whitespace().collect::<String>(),
+            r#"<divclass="a"><sectionseen="2"><p></p><p></p></section></div>"#,
+        );
+        assert!(ctx.ancestors().is_empty());
+    }
+
+    #[test]
+    fn siblings_through_fragments() {
+        let env = Env::for_test(&PathBuf::from("page.html"));
+        let mut ctx = MacroContext::new(&env, Arc::default());
+        let mut html = Node::new_element("div", HashMap::new(), vec![
+            p("a"),
+            Node::Fragment(vec![p("b"), Node::Fragment(vec![p("c")])]),
+            Node::Fragment(Vec::new()),
+            p("d"),
+        ]);
+        let mut seen = Vec::new();
+        bottom_up(&mut html, &mut ctx, &mut |ctx, node, pre_order| {
+            if let Some(id) = node.get_attr("id") {
+                seen.push((id, pre_order, ctx.sibling_index(), ctx.sibling_count()));
+            }
+        });
+        let expected = [("a", 0), ("b", 1), ("c", 2), ("d", 3)]
+            .iter()
+            .flat_map(|(id, ix)| vec![(id.to_string(), true, *ix, 4), (id.to_string(), false, *ix, 4)])
+            .collect::<Vec<_>>();
+        assert_eq!(seen, expected);
+        assert_eq!((ctx.sibling_index(), ctx.sibling_count()), (0, 1));
+    }
+}
@@ -0,0 +1,239 @@
+//! A minimal BibTeX parser, sufficient for the `<cite>` and `<bibliography>`
+//! macros. `@string`, `@preamble` and `@comment` blocks are skipped, and `#`
+//! concatenation isn't supported.
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default)]
+pub struct Bibliography {
+    pub entries: HashMap<String, Entry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// E.g. `article` or `book`.
+    pub kind: String,
+    pub key: String,
+    /// Field names are lowercased, values have their outer braces/quotes removed.
+    pub fields: HashMap<String, String>,
+}
+
+impl Bibliography {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = crate::frontend::io::try_load_text_file(path)
+            .map_err(|_| format!("failed to read {:?}", path))?;
+        Ok(Bibliography::parse(&source))
+    }
+    pub fn parse(source: &str) -> Self {
+        let mut entries = HashMap::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '@' {
+                continue;
+            }
+            let kind = take_while(&mut chars, |c| c.is_alphanumeric()).to_lowercase();
+            skip_whitespace(&mut chars);
+            let close = match chars.next() {
+                Some('{') => '}',
+                Some('(') => ')',
+                _ => continue,
+            };
+            let body = take_balanced(&mut chars, close);
+            if kind == "string" || kind == "preamble" || kind == "comment" {
+                continue;
+            }
+            if let Some(entry) = parse_entry(kind, &body) {
+                entries.insert(entry.key.clone(), entry);
+            }
+        }
+        Bibliography {entries}
+    }
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+}
+
+impl Entry {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(|x| x.as_str())
+    }
+    /// Author (or editor) names in `First Last` order.
+    pub fn authors(&self) -> Vec<String> {
+        self.field("author")
+            .or_else(|| self.field("editor"))
+            .map(|names| {
+                names
+                    .split(" and ")
+                    .map(|name| {
+                        let name = name.trim();
+                        match name.find(',') {
+                            Some(ix) => format!(
+                                "{} {}",
+                                name[ix + 1..].trim(),
+                                name[..ix].trim(),
+                            ),
+                            None => name.to_owned(),
+                        }
+                    })
+                    .filter(|x| !x.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    /// Author surnames, used for author-year citations and sorting.
+    pub fn surnames(&self) -> Vec<String> {
+        self.authors()
+            .into_iter()
+            .map(|x| x.rsplit(' ').next().unwrap_or("").to_owned())
+            .collect()
+    }
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.peek().map(|x| x.is_whitespace()).unwrap_or(false) {
+        chars.next();
+    }
+}
+
+fn take_while(chars: &mut Chars, f: impl Fn(char) -> bool) -> String {
+    let mut out = String::new();
+    while let Some(c) = chars.peek() {
+        if !f(*c) {
+            break;
+        }
+        out.push(*c);
+        chars.next();
+    }
+    out
+}
+
+/// Consumes up to (and including) the matching `close` delimiter, returning
+/// everything before it.
+fn take_balanced(chars: &mut Chars, close: char) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for c in chars.by_ref() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            c if c == close && depth == 0 => break,
+            _ => {}
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn parse_entry(kind: String, body: &str) -> Option<Entry> {
+    let mut chars = body.chars().peekable();
+    let key = take_while(&mut chars, |c| c != ',').trim().to_owned();
+    if key.is_empty() {
+        return None;
+    }
+    chars.next();
+    let mut fields = HashMap::new();
+    loop {
+        skip_whitespace(&mut chars);
+        let name = take_while(&mut chars, |c| c != '=' && c != ',')
+            .trim()
+            .to_lowercase();
+        if chars.next() != Some('=') {
+            if chars.peek().is_none() {
+                break;
+            }
+            continue;
+        }
+        skip_whitespace(&mut chars);
+        let value = match chars.peek() {
+            Some('{') => {
+                chars.next();
+                take_balanced(&mut chars, '}')
+            }
+            Some('"') => {
+                chars.next();
+                // Quotes within braces, e.g. `"a {"}quoted{"} word"`, don't
+                // end the value.
+                take_balanced(&mut chars, '"')
+            }
+            _ => take_while(&mut chars, |c| c != ',').trim().to_owned(),
+        };
+        let value = value
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace(['{', '}'], "");
+        if !name.is_empty() {
+            fields.insert(name, value);
+        }
+        take_while(&mut chars, |c| c != ',');
+        if chars.next().is_none() {
+            break;
+        }
+    }
+    Some(Entry {kind, key, fields})
+}
+
+#[cfg(test)]
+mod t_parse {
+    use super::*;
+
+    #[test]
+    fn braces_and_quotes() {
+        let bib = Bibliography::parse(r#"
+            @Article{knuth84,
+                Author = "Knuth, Donald E.",
+                title = {The {\TeX}book},
+                note = "a {"}quoted{"} word",
+                year = 1984
+            }
+        "#);
+        let entry = bib.get("knuth84").unwrap();
+        assert_eq!(entry.kind, "article");
+        assert_eq!(entry.field("author"), Some("Knuth, Donald E."));
+        assert_eq!(entry.field("title"), Some("The \\TeXbook"));
+        assert_eq!(entry.field("note"), Some("a \"quoted\" word"));
+        assert_eq!(entry.field("year"), Some("1984"));
+        assert_eq!(entry.authors(), vec!["Donald E. Knuth"]);
+        assert_eq!(entry.surnames(), vec!["Knuth"]);
+    }
+
+    #[test]
+    fn parenthesized_entries() {
+        let bib = Bibliography::parse("@book(lamport94, title = {{LaTeX}: A Document Preparation System})");
+        let entry = bib.get("lamport94").unwrap();
+        assert_eq!(entry.field("title"), Some("LaTeX: A Document Preparation System"));
+    }
+
+    #[test]
+    fn strings_and_comments_are_skipped() {
+        let bib = Bibliography::parse(r#"
+            Text outside of entries is a comment.
+            @string{tug = "TeX Users Group"}
+            @preamble{"\newcommand{\noop}[1]{}"}
+            @comment{@book{hidden, title = {Hidden}}}
+            @misc{kept, publisher = tug}
+        "#);
+        assert_eq!(bib.entries.len(), 1);
+        // `@string` abbreviations aren't expanded.
+        assert_eq!(bib.get("kept").unwrap().field("publisher"), Some("tug"));
+    }
+
+    #[test]
+    fn malformed_entries() {
+        let bib = Bibliography::parse(r#"
+            @article{, title = {No key}}
+            @misc no body
+            @book{fields, junk, title = {Title}, = {no name}}
+            @book{unterminated, title = {Unterminated
+        "#);
+        let mut keys = bib.entries.keys().map(|x| x.as_str()).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["fields", "unterminated"]);
+        let entry = bib.get("fields").unwrap();
+        assert_eq!(entry.fields.len(), 1);
+        assert_eq!(entry.field("title"), Some("Title"));
+    }
+}
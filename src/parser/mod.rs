@@ -1 +1,2 @@
-pub mod html;
\ No newline at end of file
+pub mod html;
+pub mod bibtex;
\ No newline at end of file